@@ -0,0 +1,58 @@
+use std::fs::File;
+use std::fs::OpenOptions;
+use std::io::Write;
+
+use crate::events::Attempt;
+use crate::events::Observer;
+use crate::Config;
+use crate::Error;
+use crate::Result;
+
+/// Appends one json record per attempt to a file
+pub struct AuditLog {
+    file: File,
+    config_hash: String,
+}
+
+impl AuditLog {
+    pub fn open(path: &str, config: &Config) -> Result<Self> {
+        log::info!("append audit records to: {}", path);
+        Ok(Self {
+            file: OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .map_err(Error::OpenAuditLog)?,
+            config_hash: config.redacted_hash(),
+        })
+    }
+}
+
+impl Observer for AuditLog {
+    fn attempt(&mut self, attempt: &Attempt) {
+        let mut line = serde_json::json!({
+            "timestamp": humantime::format_rfc3339_millis(attempt.timestamp).to_string(),
+            "attempt": attempt.number,
+            "check": attempt.check.to_string(),
+            "target": attempt.target,
+            "peer": attempt.peer.map(|p| p.to_string()),
+            "outcome": attempt.outcome.name(),
+            "status": attempt.status,
+            "reason": attempt.outcome.reason().map(|r| r.kind()),
+            "detail": attempt.outcome.reason().map(|r| r.to_string()),
+            "latency_ms": attempt.latency.as_millis() as u64,
+            "config_hash": self.config_hash,
+        })
+        .to_string();
+        line.push('\n');
+
+        // write the whole record at once, so concurrent writers can't interleave lines
+        if let Err(e) = self
+            .file
+            .write_all(line.as_bytes())
+            .and_then(|_| self.file.sync_data())
+        {
+            log::warn!("failed to write audit record: {}", e);
+        }
+    }
+}
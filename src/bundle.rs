@@ -0,0 +1,174 @@
+use std::collections::VecDeque;
+use std::path::PathBuf;
+use std::time::SystemTime;
+
+use crate::events::Attempt;
+use crate::events::Observer;
+use crate::Config;
+use crate::Error;
+
+/// Number of attempts a support bundle contains, the most recent ones
+const BUNDLE_ATTEMPTS: usize = 200;
+
+/// Longest body excerpt of the last response in a support bundle
+const BUNDLE_BODY_BYTES: usize = 4096;
+
+/// Writes a tarball with everything needed to look into a failed wait to a directory, once the wait failed:
+/// the redacted configuration, the last attempts, the last response and a fingerprint of the environment
+pub struct SupportBundle {
+    dir: PathBuf,
+    config: serde_json::Value,
+    attempts: VecDeque<serde_json::Value>,
+    last_response: Option<String>,
+}
+
+impl SupportBundle {
+    pub fn new(dir: &str, config: &Config) -> Self {
+        Self {
+            dir: PathBuf::from(dir),
+            config: config.redacted_json(),
+            attempts: VecDeque::new(),
+            last_response: None,
+        }
+    }
+
+    fn write(&self, error: &Error) -> std::io::Result<PathBuf> {
+        let now = SystemTime::now();
+        let seconds = now
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let name = format!("consul-online-support-{}", seconds);
+        let attempts = self
+            .attempts
+            .iter()
+            .map(|a| format!("{}\n", a))
+            .collect::<String>();
+        let files = [
+            ("error.txt", format!("{}\n", error)),
+            ("config.json", format!("{:#}\n", self.config)),
+            ("attempts.ndjson", attempts),
+            (
+                "last-response.txt",
+                self.last_response
+                    .clone()
+                    .unwrap_or_else(|| "the agent never responded\n".to_owned()),
+            ),
+            ("environment.json", format!("{:#}\n", environment(now))),
+        ];
+        let mut tar = Vec::new();
+        for (file, contents) in &files {
+            append(
+                &mut tar,
+                &format!("{}/{}", name, file),
+                contents.as_bytes(),
+                seconds,
+            );
+        }
+        // the archive ends with two empty blocks
+        tar.resize(tar.len() + 1024, 0);
+        std::fs::create_dir_all(&self.dir)?;
+        let path = self.dir.join(format!("{}.tar", name));
+        std::fs::write(&path, tar)?;
+        Ok(path)
+    }
+}
+
+impl Observer for SupportBundle {
+    fn attempt(&mut self, attempt: &Attempt) {
+        if self.attempts.len() == BUNDLE_ATTEMPTS {
+            self.attempts.pop_front();
+        }
+        self.attempts.push_back(serde_json::json!({
+            "timestamp": humantime::format_rfc3339_millis(attempt.timestamp).to_string(),
+            "attempt": attempt.number,
+            "check": attempt.check.to_string(),
+            "target": attempt.target,
+            "peer": attempt.peer.map(|p| p.to_string()),
+            "outcome": attempt.outcome.name(),
+            "status": attempt.status,
+            "reason": attempt.outcome.reason().map(|r| r.kind()),
+            "detail": attempt.outcome.reason().map(|r| r.to_string()),
+            "latency_ms": attempt.latency.as_millis() as u64,
+        }));
+        if let (Some(status), Some(body)) = (attempt.status, attempt.body) {
+            let headers = attempt
+                .headers
+                .iter()
+                .map(|(name, value)| format!("{}: {}\n", name, value))
+                .collect::<String>();
+            self.last_response = Some(format!(
+                "GET {}\nstatus {}\n{}\n{}\n",
+                attempt.target,
+                status,
+                headers,
+                crate::body_prefix(body, BUNDLE_BODY_BYTES)
+            ));
+        }
+    }
+
+    fn finished(&mut self, result: &std::result::Result<(), Error>) {
+        let error = match result {
+            Ok(()) => return,
+            Err(error) => error,
+        };
+        match self.write(error) {
+            Ok(path) => log::warn!("wrote support bundle to {}", path.display()),
+            Err(e) => log::warn!(
+                "failed to write support bundle to {}: {}",
+                self.dir.display(),
+                e
+            ),
+        }
+    }
+}
+
+/// Version, platform and which relevant environment variables are set, without their values
+fn environment(now: SystemTime) -> serde_json::Value {
+    let mut variables = std::env::vars_os()
+        .filter_map(|(name, _)| name.into_string().ok())
+        .filter(|name| {
+            let upper = name.to_uppercase();
+            upper.starts_with("CONSUL_") || upper.ends_with("_PROXY")
+        })
+        .collect::<Vec<_>>();
+    variables.sort();
+    serde_json::json!({
+        "timestamp": humantime::format_rfc3339_millis(now).to_string(),
+        "version": env!("CARGO_PKG_VERSION"),
+        "tls_backend": crate::TLS_BACKEND,
+        "os": std::env::consts::OS,
+        "arch": std::env::consts::ARCH,
+        "hostname": std::fs::read_to_string("/proc/sys/kernel/hostname").ok().map(|h| h.trim().to_owned()),
+        "pid": std::process::id(),
+        "environment_variables_set": variables,
+    })
+}
+
+/// Append a file to a ustar archive
+fn append(tar: &mut Vec<u8>, path: &str, contents: &[u8], mtime: u64) {
+    let mut header = [0u8; 512];
+    let field = |header: &mut [u8; 512], offset: usize, value: &[u8]| {
+        header[offset..offset + value.len()].copy_from_slice(value)
+    };
+    field(&mut header, 0, &path.as_bytes()[..path.len().min(100)]);
+    field(&mut header, 100, b"0000644\0");
+    field(&mut header, 108, b"0000000\0");
+    field(&mut header, 116, b"0000000\0");
+    field(
+        &mut header,
+        124,
+        format!("{:011o}\0", contents.len()).as_bytes(),
+    );
+    field(&mut header, 136, format!("{:011o}\0", mtime).as_bytes());
+    // the checksum is computed with its own field set to spaces
+    field(&mut header, 148, b"        ");
+    header[156] = b'0';
+    field(&mut header, 257, b"ustar\0");
+    field(&mut header, 263, b"00");
+    let checksum = header.iter().map(|b| *b as u32).sum::<u32>();
+    field(&mut header, 148, format!("{:06o}\0 ", checksum).as_bytes());
+    tar.extend_from_slice(&header);
+    tar.extend_from_slice(contents);
+    tar.resize(tar.len().next_multiple_of(512), 0);
+}
@@ -0,0 +1,1198 @@
+use std::fmt::Display;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+use std::time::Instant;
+
+use percent_encoding::utf8_percent_encode;
+use percent_encoding::AsciiSet;
+use percent_encoding::NON_ALPHANUMERIC;
+
+#[cfg(feature = "native-transport")]
+use crate::dns;
+use crate::events::NotReadyReason;
+use crate::events::Outcome;
+use crate::interpret::Accept;
+use crate::interpret::Json;
+use crate::interpret::Response;
+use crate::interpret::ResponseInterpreter;
+use crate::interpret::StatusOnly;
+use crate::raft::RaftConfiguration;
+use crate::Client;
+use crate::Error;
+use crate::Evaluation;
+
+/// Path of the raft configuration endpoint, polled by [Check::Raft]
+pub(crate) const RAFT_PATH: &str = "/v1/operator/raft/configuration";
+
+/// Path of the endpoint with the address of the raft leader, polled by [Check::Leader]
+pub(crate) const LEADER_PATH: &str = "/v1/status/leader";
+
+/// Addresses of the raft peers, needs no acl token
+const STATUS_PEERS_PATH: &str = "/v1/status/peers";
+
+/// Path of the catalog endpoint whose index is watched by [Check::CatalogStable]
+const CATALOG_PATH: &str = "/v1/catalog/services";
+
+/// Key the enterprise snapshot agent locks once it is the leader of the snapshot agents, unless configured otherwise
+const SNAPSHOT_LOCK_KEY: &str = "consul-snapshot/lock";
+
+/// A condition that must be met before consul is considered online
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Check {
+    /// The raft configuration can be read, meaning the cluster has a leader
+    Raft,
+    /// The agent knows the address of the raft leader. Unlike [Check::Raft], needs no acl permissions
+    Leader,
+    /// The connect sidecar proxy for a service is registered with the agent and passing
+    XdsService(String),
+    /// The agent dns interface resolves a name, one outside of the consul domain tests recursion.
+    /// The server defaults to port 8600 on the agent host
+    DnsRecurse {
+        name: String,
+        server: Option<String>,
+    },
+    /// The catalog index did not change for at least this long
+    CatalogStable(Duration),
+    /// The acl configuration of the agent matches the expectation. Fails immediately if it does not
+    Acl(AclExpectation),
+    /// The agent responds to /v1/agent/self with these field values, by default: it considers itself an alive
+    /// member of the cluster
+    Agent(AgentFields),
+    /// Autopilot reports all servers as healthy
+    Autopilot,
+    /// The raft configuration has at least this many servers
+    Peers(usize),
+    /// The connect ca has an active root certificate
+    ConnectCa,
+    /// A snapshot agent (enterprise) elected its leader: a session holds its lock key, the default key if None
+    SnapshotAgent(Option<String>),
+    /// Intentions allow connections from the source to the destination service
+    Intention { source: String, destination: String },
+    /// A service registered in the catalog has at least this many instances with passing health checks
+    Service { name: String, min_instances: usize },
+    /// Several services each have at least one passing instance, [crate::Config::effective_checks] turns this into
+    /// a [Check::Service] per service
+    Services(Vec<String>),
+    /// A node joined the cluster and is registered in the catalog
+    Node(String),
+    /// At least this many keys exist below a prefix in the kv store
+    KvPrefix { prefix: String, min_keys: usize },
+    /// A check of a program using the library, it can't be given on the command line
+    Custom(CustomCheck),
+}
+
+/// Requests an endpoint of the agent and decides with an interpreter of its own, see [Check::Custom]
+#[derive(Clone)]
+pub struct CustomCheck {
+    /// Identifies the check in logs and results
+    pub name: String,
+    /// Path and query of the endpoint, like `/v1/kv/app/config`
+    pub path: String,
+    pub interpreter: Arc<dyn ResponseInterpreter>,
+}
+
+impl std::fmt::Debug for CustomCheck {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("CustomCheck")
+            .field("name", &self.name)
+            .field("path", &self.path)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Checks are equal if they request the same endpoint with the same interpreter
+impl PartialEq for CustomCheck {
+    fn eq(&self, other: &Self) -> bool {
+        self.name == other.name
+            && self.path == other.path
+            && Arc::ptr_eq(&self.interpreter, &other.interpreter)
+    }
+}
+
+impl Eq for CustomCheck {}
+
+/// A preset of checks for a common kind of agent
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Profile {
+    /// A server in a cluster of at least three: leader, healthy autopilot, three peers
+    Server,
+    /// A client agent: the agent is alive and the cluster has a leader
+    Client,
+    /// A cluster with service mesh: the connect ca is ready, and optionally the sidecar of a service
+    Mesh(Option<String>),
+    /// The dns interface of the agent resolves consul services
+    Dns,
+}
+
+impl Profile {
+    pub fn checks(&self) -> Vec<Check> {
+        match self {
+            Profile::Server => vec![Check::Raft, Check::Autopilot, Check::Peers(3)],
+            Profile::Client => vec![Check::Agent(AgentFields::default()), Check::Raft],
+            Profile::Mesh(service) => [Check::Raft, Check::ConnectCa]
+                .into_iter()
+                .chain(service.clone().map(Check::XdsService))
+                .collect(),
+            Profile::Dns => vec![
+                Check::Raft,
+                Check::DnsRecurse {
+                    name: "consul.service.consul".to_owned(),
+                    server: None,
+                },
+            ],
+        }
+    }
+}
+
+impl FromStr for Profile {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.split_once(':') {
+            None if s == "server" => Ok(Profile::Server),
+            None if s == "client" => Ok(Profile::Client),
+            None if s == "mesh" => Ok(Profile::Mesh(None)),
+            Some(("mesh", service)) if !service.is_empty() => {
+                Ok(Profile::Mesh(Some(service.to_owned())))
+            }
+            None if s == "dns" => Ok(Profile::Dns),
+            _ => Err(format!(
+                "unknown profile {}, expected server, client, mesh[:<service>] or dns",
+                s
+            )),
+        }
+    }
+}
+
+impl Display for Profile {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Profile::Server => write!(f, "server"),
+            Profile::Client => write!(f, "client"),
+            Profile::Mesh(None) => write!(f, "mesh"),
+            Profile::Mesh(Some(service)) => write!(f, "mesh:{}", service),
+            Profile::Dns => write!(f, "dns"),
+        }
+    }
+}
+
+/// Expected acl configuration of the agent
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AclExpectation {
+    pub enabled: bool,
+    pub default_policy: Option<String>,
+}
+
+impl FromStr for AclExpectation {
+    type Err = String;
+
+    /// Parse an expectation like "enabled,default-policy=deny"
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let mut parts = s.split(',').map(str::trim);
+        let enabled = match parts.next() {
+            Some("enabled") => true,
+            Some("disabled") => false,
+            _ => return Err(format!("expected enabled or disabled, got: {}", s)),
+        };
+        let mut default_policy = None;
+        for part in parts {
+            match part.split_once('=') {
+                Some(("default-policy", policy @ ("allow" | "deny"))) => {
+                    default_policy = Some(policy.to_owned())
+                }
+                _ => return Err(format!("expected default-policy=allow|deny, got: {}", part)),
+            }
+        }
+        Ok(AclExpectation {
+            enabled,
+            default_policy,
+        })
+    }
+}
+
+impl Display for AclExpectation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", if self.enabled { "enabled" } else { "disabled" })?;
+        if let Some(policy) = &self.default_policy {
+            write!(f, ",default-policy={}", policy)?;
+        }
+        Ok(())
+    }
+}
+
+/// A header successful responses must have, written as "X-Consul-Effective-Consistency: leader", or only the name
+/// if any value will do
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HeaderExpectation {
+    pub name: String,
+    pub value: Option<String>,
+}
+
+impl HeaderExpectation {
+    /// Why the headers of a response don't meet the expectation, None if they do
+    pub(crate) fn mismatch(&self, headers: &[(String, String)]) -> Option<String> {
+        let actual = headers
+            .iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case(&self.name))
+            .map(|(_, value)| value.as_str());
+        match (actual, &self.value) {
+            (None, _) => Some(format!("response has no {} header", self.name)),
+            (Some(actual), Some(expected)) if actual != expected => Some(format!(
+                "response header {} is {}, expected {}",
+                self.name, actual, expected
+            )),
+            _ => None,
+        }
+    }
+}
+
+impl FromStr for HeaderExpectation {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let (name, value) = match s.split_once(':') {
+            Some((name, value)) => (name.trim(), Some(value.trim().to_owned())),
+            None => (s.trim(), None),
+        };
+        if name.is_empty() || name.contains(char::is_whitespace) {
+            return Err(format!("expected <header>[: <value>], got: {}", s));
+        }
+        Ok(HeaderExpectation {
+            name: name.to_owned(),
+            value,
+        })
+    }
+}
+
+impl Display for HeaderExpectation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.value {
+            Some(value) => write!(f, "{}: {}", self.name, value),
+            None => write!(f, "{}", self.name),
+        }
+    }
+}
+
+/// Minimum numbers of passing instances of services, written as "web=2,api=1"
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct MinInstances(pub Vec<(String, usize)>);
+
+impl MinInstances {
+    /// Minimum number of passing instances of `service`, if one is given
+    pub fn of(&self, service: &str) -> Option<usize> {
+        self.0
+            .iter()
+            .find(|(name, _)| name == service)
+            .map(|(_, min)| *min)
+    }
+}
+
+impl FromStr for MinInstances {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        s.split(',')
+            .map(|entry| match entry.split_once('=') {
+                Some((name, min)) if !name.trim().is_empty() => min
+                    .trim()
+                    .parse()
+                    .map(|min| (name.trim().to_owned(), min))
+                    .map_err(|e| format!("invalid instance count for {}: {}", name, e)),
+                _ => Err(format!("expected <service>=<count>, got: {}", entry)),
+            })
+            .collect::<std::result::Result<_, _>>()
+            .map(MinInstances)
+    }
+}
+
+impl Display for MinInstances {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let entries = self.0.iter().map(|(name, min)| format!("{}={}", name, min));
+        write!(f, "{}", entries.collect::<Vec<_>>().join(","))
+    }
+}
+
+/// Values of fields of /v1/agent/self, written as "Member.Status=alive,Config.Datacenter=dc1".
+/// Nested fields are separated by dots, Member.Status takes the serf status names none, alive, leaving, left and
+/// failed as well as their numbers
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AgentFields(pub Vec<(String, String)>);
+
+impl Default for AgentFields {
+    /// The agent is an alive member of the cluster
+    fn default() -> Self {
+        Self(vec![("Member.Status".to_owned(), "alive".to_owned())])
+    }
+}
+
+/// Serf member status names, by their number
+const MEMBER_STATUS: [&str; 5] = ["none", "alive", "leaving", "left", "failed"];
+
+impl AgentFields {
+    /// Why the fields of the agent don't have the expected values, None if all do
+    fn mismatch(&self, agent: &serde_json::Value) -> Option<String> {
+        self.0.iter().find_map(|(field, expected)| {
+            let pointer = format!("/{}", field.replace('.', "/"));
+            let actual = match agent.pointer(&pointer) {
+                Some(serde_json::Value::String(s)) => s.clone(),
+                Some(value) => value.to_string(),
+                None => return Some(format!("agent has no field {}", field)),
+            };
+            let status_name = match field.as_str() {
+                "Member.Status" => actual
+                    .parse::<usize>()
+                    .ok()
+                    .and_then(|n| MEMBER_STATUS.get(n).copied()),
+                _ => None,
+            };
+            match actual == *expected || status_name == Some(expected.as_str()) {
+                true => None,
+                false => Some(format!(
+                    "agent field {} is {}, expected {}",
+                    field,
+                    status_name.unwrap_or(&actual),
+                    expected
+                )),
+            }
+        })
+    }
+}
+
+impl FromStr for AgentFields {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        s.split(',')
+            .map(|field| match field.split_once('=') {
+                Some((name, value)) if !name.is_empty() => {
+                    Ok((name.trim().to_owned(), value.trim().to_owned()))
+                }
+                _ => Err(format!("expected <field>=<value>, got: {}", field)),
+            })
+            .collect::<std::result::Result<_, _>>()
+            .map(AgentFields)
+    }
+}
+
+impl Display for AgentFields {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let fields = self
+            .0
+            .iter()
+            .map(|(name, value)| format!("{}={}", name, value));
+        write!(f, "{}", fields.collect::<Vec<_>>().join(","))
+    }
+}
+
+impl Check {
+    /// Whether the endpoint of the check supports blocking queries, see [crate::Config::blocking_queries]
+    pub fn supports_blocking(&self) -> bool {
+        matches!(
+            self,
+            Check::CatalogStable(_)
+                | Check::Service { .. }
+                | Check::Node(_)
+                | Check::SnapshotAgent(_)
+                | Check::KvPrefix { .. }
+        )
+    }
+
+    /// Whether the check only uses endpoints that never need an acl token, see [crate::Config::no_token]
+    pub fn tokenless(&self) -> bool {
+        matches!(
+            self,
+            Check::Leader | Check::Peers(_) | Check::DnsRecurse { .. }
+        )
+    }
+}
+
+impl FromStr for Check {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let (kind, arg) = match s.split_once(':') {
+            Some((kind, arg)) => (kind, Some(arg)),
+            None => (s, None),
+        };
+        let required = |arg: Option<&str>| match arg {
+            Some(arg) if !arg.is_empty() => Ok(arg.to_owned()),
+            _ => Err(format!(
+                "check {} requires an argument: {}:<arg>",
+                kind, kind
+            )),
+        };
+        match kind {
+            "raft" => Ok(Check::Raft),
+            "leader" => Ok(Check::Leader),
+            "xds-service" => Ok(Check::XdsService(required(arg)?)),
+            "dns-recurse" => {
+                let arg = required(arg)?;
+                Ok(match arg.split_once('@') {
+                    Some((name, server)) => Check::DnsRecurse {
+                        name: name.to_owned(),
+                        server: Some(server.to_owned()),
+                    },
+                    None => Check::DnsRecurse {
+                        name: arg,
+                        server: None,
+                    },
+                })
+            }
+            "catalog-stable" => humantime::parse_duration(&required(arg)?)
+                .map(Check::CatalogStable)
+                .map_err(|e| format!("invalid duration for check {}: {}", kind, e)),
+            "acl" => required(arg)?.parse().map(Check::Acl),
+            "agent" => match arg {
+                None => Ok(Check::Agent(AgentFields::default())),
+                Some("responds") => Ok(Check::Agent(AgentFields(Vec::new()))),
+                Some(fields) => fields.parse().map(Check::Agent),
+            },
+            "autopilot" => Ok(Check::Autopilot),
+            "peers" => required(arg)?
+                .parse()
+                .map(Check::Peers)
+                .map_err(|e| format!("invalid peer count for check {}: {}", kind, e)),
+            "connect-ca" => Ok(Check::ConnectCa),
+            "snapshot-agent" => Ok(Check::SnapshotAgent(
+                arg.map(|key| key.trim_matches('/').to_owned())
+                    .filter(|key| !key.is_empty()),
+            )),
+            "intention" => match required(arg)?.split_once(':') {
+                Some((source, destination)) if !source.is_empty() && !destination.is_empty() => {
+                    Ok(Check::Intention {
+                        source: source.to_owned(),
+                        destination: destination.to_owned(),
+                    })
+                }
+                _ => Err(format!(
+                    "check {} requires a service pair: {}:<source>:<destination>",
+                    kind, kind
+                )),
+            },
+            "service" => {
+                let arg = required(arg)?;
+                let (name, min_instances) = match arg.split_once('=') {
+                    Some((name, min)) => (
+                        name.to_owned(),
+                        min.parse().map_err(|e| {
+                            format!("invalid instance count for check {}: {}", kind, e)
+                        })?,
+                    ),
+                    None => (arg, 1),
+                };
+                Ok(Check::Service {
+                    name,
+                    min_instances,
+                })
+            }
+            "services" => Ok(Check::Services(
+                required(arg)?
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|s| !s.is_empty())
+                    .map(str::to_owned)
+                    .collect(),
+            )),
+            "node" => Ok(Check::Node(required(arg)?)),
+            "kv-prefix" => {
+                let arg = required(arg)?;
+                let (prefix, min_keys) = match arg.rsplit_once(':') {
+                    Some((prefix, option)) if option.starts_with("min-keys=") => (
+                        prefix,
+                        option["min-keys=".len()..]
+                            .parse()
+                            .map_err(|e| format!("invalid key count for check {}: {}", kind, e))?,
+                    ),
+                    _ => (arg.as_str(), 1),
+                };
+                Ok(Check::KvPrefix {
+                    prefix: prefix.trim_start_matches('/').to_owned(),
+                    min_keys,
+                })
+            }
+            _ => Err(format!("unknown check: {}", s)),
+        }
+    }
+}
+
+impl Display for Check {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Check::Raft => write!(f, "raft"),
+            Check::Leader => write!(f, "leader"),
+            Check::XdsService(service) => write!(f, "xds-service:{}", service),
+            Check::DnsRecurse { name, server } => match server {
+                Some(server) => write!(f, "dns-recurse:{}@{}", name, server),
+                None => write!(f, "dns-recurse:{}", name),
+            },
+            Check::CatalogStable(window) => {
+                write!(f, "catalog-stable:{}", humantime::format_duration(*window))
+            }
+            Check::Acl(expected) => write!(f, "acl:{}", expected),
+            Check::Agent(fields) if *fields == AgentFields::default() => write!(f, "agent"),
+            Check::Agent(fields) if fields.0.is_empty() => write!(f, "agent:responds"),
+            Check::Agent(fields) => write!(f, "agent:{}", fields),
+            Check::Autopilot => write!(f, "autopilot"),
+            Check::Peers(n) => write!(f, "peers:{}", n),
+            Check::ConnectCa => write!(f, "connect-ca"),
+            Check::SnapshotAgent(None) => write!(f, "snapshot-agent"),
+            Check::SnapshotAgent(Some(key)) => write!(f, "snapshot-agent:{}", key),
+            Check::Intention {
+                source,
+                destination,
+            } => write!(f, "intention:{}:{}", source, destination),
+            Check::Service {
+                name,
+                min_instances: 1,
+            } => write!(f, "service:{}", name),
+            Check::Service {
+                name,
+                min_instances,
+            } => write!(f, "service:{}={}", name, min_instances),
+            Check::Services(names) => write!(f, "services:{}", names.join(",")),
+            Check::Node(node) => write!(f, "node:{}", node),
+            Check::KvPrefix {
+                prefix,
+                min_keys: 1,
+            } => write!(f, "kv-prefix:{}", prefix),
+            Check::KvPrefix { prefix, min_keys } => {
+                write!(f, "kv-prefix:{}:min-keys={}", prefix, min_keys)
+            }
+            Check::Custom(custom) => write!(f, "custom:{}", custom.name),
+        }
+    }
+}
+
+/// How a failing check affects readiness
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Severity {
+    /// Consul is not online while the check fails
+    #[default]
+    Required,
+    /// Consul is degraded while the check fails but all required checks pass
+    Degraded,
+}
+
+impl FromStr for Severity {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "required" => Ok(Severity::Required),
+            "degraded" => Ok(Severity::Degraded),
+            _ => Err(format!("severity must be required or degraded: {}", s)),
+        }
+    }
+}
+
+impl Display for Severity {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Severity::Required => write!(f, "required"),
+            Severity::Degraded => write!(f, "degraded"),
+        }
+    }
+}
+
+/// A check with optional overrides of the request timeout, the polling interval and the severity, and the phase it
+/// belongs to, written as "<check>[;timeout=<duration>][;interval=<duration>][;severity=required|degraded][;phase=<name>]",
+/// example: "autopilot;timeout=2s;interval=30s"
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CheckSpec {
+    pub check: Check,
+    /// Request timeout for this check instead of the one derived from the global interval and timeout
+    pub timeout: Option<Duration>,
+    /// Evaluate this check at this interval instead of the global one, the last result counts in between
+    pub interval: Option<Duration>,
+    pub severity: Severity,
+    /// Name of the [Phase] the check is evaluated in, the last phase if not set
+    pub phase: Option<String>,
+}
+
+impl From<Check> for CheckSpec {
+    fn from(check: Check) -> Self {
+        Self {
+            check,
+            timeout: None,
+            interval: None,
+            severity: Severity::Required,
+            phase: None,
+        }
+    }
+}
+
+impl FromStr for CheckSpec {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let mut parts = s.split(';');
+        let mut spec = CheckSpec::from(parts.next().unwrap_or_default().parse::<Check>()?);
+        for option in parts.filter(|o| !o.is_empty()) {
+            let (key, value) = option
+                .split_once('=')
+                .ok_or_else(|| format!("check option must be <key>=<value>: {}", option))?;
+            match key {
+                "severity" => {
+                    spec.severity = value.parse()?;
+                    continue;
+                }
+                "phase" if value.is_empty() => {
+                    return Err("check phase must not be empty".to_owned())
+                }
+                "phase" => {
+                    spec.phase = Some(value.to_owned());
+                    continue;
+                }
+                _ => {}
+            }
+            let duration = humantime::parse_duration(value)
+                .map_err(|e| format!("invalid duration for check option {}: {}", key, e))?;
+            match key {
+                "timeout" => spec.timeout = Some(duration),
+                "interval" if duration.is_zero() => {
+                    return Err("check interval must not be zero".to_owned())
+                }
+                "interval" => spec.interval = Some(duration),
+                _ => return Err(format!("unknown check option: {}", key)),
+            }
+        }
+        Ok(spec)
+    }
+}
+
+impl Display for CheckSpec {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.check)?;
+        if let Some(timeout) = self.timeout {
+            write!(f, ";timeout={}", humantime::format_duration(timeout))?;
+        }
+        if let Some(interval) = self.interval {
+            write!(f, ";interval={}", humantime::format_duration(interval))?;
+        }
+        if self.severity != Severity::Required {
+            write!(f, ";severity={}", self.severity)?;
+        }
+        if let Some(phase) = &self.phase {
+            write!(f, ";phase={}", phase)?;
+        }
+        Ok(())
+    }
+}
+
+/// A part of the wait with its own time budget and checks, written as "<name>=<duration>". Phases run one after
+/// the other, each once the checks of the previous one passed
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Phase {
+    pub name: String,
+    pub budget: Duration,
+}
+
+impl Phase {
+    /// The check of a phase that has none: "connect" waits for the agent to respond, "leader" for a raft leader
+    pub fn default_check(&self) -> Option<Check> {
+        match self.name.as_str() {
+            "connect" => Some(Check::Agent(AgentFields(Vec::new()))),
+            "leader" => Some(Check::Leader),
+            _ => None,
+        }
+    }
+}
+
+impl FromStr for Phase {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let (name, budget) = s
+            .split_once('=')
+            .filter(|(name, _)| !name.is_empty())
+            .ok_or_else(|| format!("phase must be <name>=<duration>: {}", s))?;
+        Ok(Phase {
+            name: name.to_owned(),
+            budget: humantime::parse_duration(budget)
+                .map_err(|e| format!("invalid budget for phase {}: {}", name, e))?,
+        })
+    }
+}
+
+impl Display for Phase {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}={}",
+            self.name,
+            humantime::format_duration(self.budget)
+        )
+    }
+}
+
+/// Replace `{env:NAME}` placeholders in a check or one of its arguments with what `lookup` returns for the name,
+/// fails if a variable is not set or a placeholder is not closed
+pub fn expand_env(
+    s: &str,
+    lookup: impl Fn(&str) -> Option<String>,
+) -> std::result::Result<String, String> {
+    let mut expanded = String::with_capacity(s.len());
+    let mut rest = s;
+    while let Some(start) = rest.find("{env:") {
+        expanded.push_str(&rest[..start]);
+        let placeholder = &rest[start + "{env:".len()..];
+        let end = placeholder
+            .find('}')
+            .ok_or_else(|| "unterminated placeholder".to_owned())?;
+        let name = &placeholder[..end];
+        if name.is_empty() {
+            return Err("placeholder without a variable name".to_owned());
+        }
+        let value =
+            lookup(name).ok_or_else(|| format!("environment variable {} is not set", name))?;
+        expanded.push_str(&value);
+        rest = &placeholder[end + 1..];
+    }
+    expanded.push_str(rest);
+    Ok(expanded)
+}
+
+fn unsatisfied(detail: String) -> Outcome {
+    Outcome::NotReady(NotReadyReason::Unsatisfied(detail))
+}
+
+fn encode(s: &str) -> String {
+    url::form_urlencoded::byte_serialize(s.as_bytes()).collect()
+}
+
+/// Characters encoded in a segment of a path: all but the unreserved characters of rfc 3986
+const PATH_SEGMENT: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'-')
+    .remove(b'.')
+    .remove(b'_')
+    .remove(b'~');
+
+/// A kv key or prefix as part of a path: each segment is percent-encoded, the `/` between them are kept
+fn encode_key(key: &str) -> String {
+    key.split('/')
+        .map(|segment| utf8_percent_encode(segment, PATH_SEGMENT).to_string())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+fn json(body: &str) -> serde_json::Value {
+    serde_json::from_str(body).unwrap_or_default()
+}
+
+/// Tracks for how long the catalog index has not changed
+#[derive(Default)]
+pub(crate) struct IndexTracker {
+    current: Option<(u64, Instant)>,
+}
+
+impl IndexTracker {
+    /// Record the current index, returns for how long it did not change
+    fn observe(&mut self, index: u64) -> Duration {
+        match self.current {
+            Some((current, since)) if current == index => since.elapsed(),
+            _ => {
+                self.current = Some((index, Instant::now()));
+                Duration::ZERO
+            }
+        }
+    }
+}
+
+/// Evaluate [Check::CatalogStable], which needs to keep state between attempts
+pub(crate) fn catalog_stable(
+    window: Duration,
+    client: &Client,
+    timeout: Duration,
+    tracker: &mut IndexTracker,
+) -> Evaluation {
+    let mut evaluation =
+        client.evaluate(
+            CATALOG_PATH,
+            timeout,
+            &|response: &Response| match response.index {
+                Some(_) => Outcome::Online,
+                None => unsatisfied("catalog response has no X-Consul-Index header".to_owned()),
+            },
+        );
+    let index = evaluation.reply.as_ref().and_then(|reply| reply.index);
+    if let (Outcome::Online, Some(index)) = (&evaluation.outcome, index) {
+        let stable_for = tracker.observe(index);
+        if stable_for < window {
+            evaluation.outcome =
+                Outcome::NotReady(NotReadyReason::CatalogUnsettled { index, stable_for });
+        }
+    }
+    evaluation
+}
+
+/// The leader address in a response of [LEADER_PATH], None while there is no leader
+pub(crate) fn leader_address(body: &str) -> Option<String> {
+    serde_json::from_str::<String>(body)
+        .ok()
+        .filter(|leader| !leader.is_empty())
+}
+
+/// Evaluate all checks except [Check::Raft], [Check::Leader] and [Check::CatalogStable], which are handled by the
+/// polling loop
+pub(crate) fn evaluate(check: &Check, client: &Client, timeout: Duration) -> Evaluation {
+    match check {
+        Check::Raft => client.evaluate(RAFT_PATH, timeout, &StatusOnly),
+        Check::Leader => client.evaluate(
+            LEADER_PATH,
+            timeout,
+            &Json::new(|leader: &String| match leader.is_empty() {
+                false => Outcome::Online,
+                true => Outcome::NotReady(NotReadyReason::NoLeader),
+            }),
+        ),
+        Check::CatalogStable(window) => {
+            catalog_stable(*window, client, timeout, &mut IndexTracker::default())
+        }
+        Check::XdsService(service) => xds_service(service, client, timeout),
+        Check::DnsRecurse { name, server } => dns_recurse(name, server.as_deref(), client, timeout),
+        Check::Acl(expected) => acl(expected, client, timeout),
+        Check::Agent(fields) => client.evaluate(
+            "/v1/agent/self",
+            timeout,
+            &|response: &Response| match fields.mismatch(&json(response.body)) {
+                None => Outcome::Online,
+                Some(mismatch) => unsatisfied(mismatch),
+            },
+        ),
+        // autopilot responds with 429 while unhealthy
+        Check::Autopilot => client.evaluate(
+            "/v1/operator/autopilot/health",
+            timeout,
+            &Accept::new(&[200, 429], |response: &Response| {
+                let health = json(response.body);
+                match health["Healthy"].as_bool() {
+                    Some(true) => Outcome::Online,
+                    _ => unsatisfied(format!(
+                        "autopilot reports an unhealthy cluster, failure tolerance {}",
+                        health["FailureTolerance"]
+                    )),
+                }
+            }),
+        ),
+        // the peer addresses of the status endpoint need no token, unlike the raft configuration
+        Check::Peers(min) if client.tokenless => client.evaluate(
+            STATUS_PEERS_PATH,
+            timeout,
+            &Json::new(|peers: &Vec<String>| match peers.len() {
+                n if n >= *min => Outcome::Online,
+                n => unsatisfied(format!("{} of {} raft peers present", n, min)),
+            }),
+        ),
+        Check::Peers(min) => client.evaluate(RAFT_PATH, timeout, &|response: &Response| {
+            match RaftConfiguration::parse(response.body).map(|raft| raft.servers.len()) {
+                Some(n) if n >= *min => Outcome::Online,
+                n => unsatisfied(format!(
+                    "{} of {} raft peers present",
+                    n.unwrap_or_default(),
+                    min
+                )),
+            }
+        }),
+        Check::ConnectCa => {
+            client.evaluate("/v1/connect/ca/roots", timeout, &|response: &Response| {
+                let roots = json(response.body);
+                let active = roots["Roots"]
+                    .as_array()
+                    .is_some_and(|roots| roots.iter().any(|r| r["Active"] == true));
+                match active {
+                    true => Outcome::Online,
+                    false => unsatisfied("connect ca has no active root".to_owned()),
+                }
+            })
+        }
+        Check::SnapshotAgent(key) => snapshot_agent(key.as_deref(), client, timeout),
+        Check::Service {
+            name,
+            min_instances,
+        } => service(name, *min_instances, client, timeout),
+        Check::Services(names) => {
+            // the first service that is not online decides
+            let mut last = None;
+            for name in names {
+                let evaluation = service(name, 1, client, timeout);
+                if !matches!(evaluation.outcome, Outcome::Online) {
+                    return evaluation;
+                }
+                last = Some(evaluation);
+            }
+            last.unwrap_or_else(|| Evaluation {
+                target: String::new(),
+                reply: None,
+                error: None,
+                outcome: Outcome::Online,
+            })
+        }
+        Check::KvPrefix { prefix, min_keys } => {
+            // lists the keys below the prefix, responds with 404 while there are none
+            let path = format!("/v1/kv/{}?keys", encode_key(prefix));
+            client.evaluate(
+                &path,
+                timeout,
+                &Accept::new(&[200, 404], |response: &Response| {
+                    let keys = json(response.body).as_array().map_or(0, Vec::len);
+                    match keys >= *min_keys {
+                        true => Outcome::Online,
+                        false => unsatisfied(format!(
+                            "{} of {} keys below {} in the kv store",
+                            keys, min_keys, prefix
+                        )),
+                    }
+                }),
+            )
+        }
+        Check::Node(name) => {
+            // responds with null while the node is not registered, newer versions with 404
+            let path = format!("/v1/catalog/node/{}", encode(name));
+            client.evaluate(
+                &path,
+                timeout,
+                &Accept::new(&[200, 404], |response: &Response| {
+                    match json(response.body)["Node"]["Node"].as_str() {
+                        Some(_) if response.status == 200 => Outcome::Online,
+                        _ => unsatisfied(format!("node {} is not registered in the catalog", name)),
+                    }
+                }),
+            )
+        }
+        Check::Intention {
+            source,
+            destination,
+        } => {
+            let path = format!(
+                "/v1/connect/intentions/check?source={}&destination={}",
+                encode(source),
+                encode(destination)
+            );
+            client.evaluate(
+                &path,
+                timeout,
+                &|response: &Response| match json(response.body)["Allowed"].as_bool() {
+                    Some(true) => Outcome::Online,
+                    Some(false) => unsatisfied(format!(
+                        "intentions deny connections from {} to {}",
+                        source, destination
+                    )),
+                    None => unsatisfied("intention check response has no Allowed field".to_owned()),
+                },
+            )
+        }
+        Check::Custom(custom) => {
+            client.evaluate(&custom.path, timeout, custom.interpreter.as_ref())
+        }
+    }
+}
+
+fn service(service: &str, min_instances: usize, client: &Client, timeout: Duration) -> Evaluation {
+    let path = format!("/v1/health/service/{}?passing=true", encode(service));
+    client.evaluate(
+        &path,
+        timeout,
+        &|response: &Response| match json(response.body)
+            .as_array()
+            .map(Vec::len)
+            .unwrap_or_default()
+        {
+            0 if min_instances <= 1 => {
+                unsatisfied(format!("service {} has no passing instances", service))
+            }
+            n if n < min_instances => unsatisfied(format!(
+                "service {} has {} of {} passing instances",
+                service, n, min_instances
+            )),
+            n => {
+                log::debug!("service {} has {} passing instances", service, n);
+                Outcome::Online
+            }
+        },
+    )
+}
+
+fn snapshot_agent(key: Option<&str>, client: &Client, timeout: Duration) -> Evaluation {
+    let key = key.unwrap_or(SNAPSHOT_LOCK_KEY);
+    // the kv endpoint responds with 404 while the key does not exist
+    let path = format!("/v1/kv/{}", encode_key(key));
+    client.evaluate(
+        &path,
+        timeout,
+        &Accept::new(&[200, 404], |response: &Response| {
+            let session = json(response.body)[0]["Session"]
+                .as_str()
+                .filter(|s| !s.is_empty())
+                .map(str::to_owned);
+            match (response.status, session) {
+                (200, Some(session)) => {
+                    log::debug!("snapshot agent lock {} is held by session {}", key, session);
+                    Outcome::Online
+                }
+                (200, None) => unsatisfied(format!("snapshot agent lock {} is not held", key)),
+                _ => unsatisfied(format!(
+                    "snapshot agent lock {} does not exist, no snapshot agent elected a leader yet",
+                    key
+                )),
+            }
+        }),
+    )
+}
+
+fn acl(expected: &AclExpectation, client: &Client, timeout: Duration) -> Evaluation {
+    let mut evaluation = client.evaluate("/v1/agent/self", timeout, &|response: &Response| {
+        let config = &json(response.body)["DebugConfig"];
+        let enabled = match config["ACLsEnabled"].as_bool() {
+            Some(enabled) => enabled,
+            None => return unsatisfied("agent does not report its acl configuration".to_owned()),
+        };
+        // moved into ACLResolverSettings in consul 1.11
+        let policy = config["ACLResolverSettings"]["ACLDefaultPolicy"]
+            .as_str()
+            .or_else(|| config["ACLDefaultPolicy"].as_str());
+        let mismatch = if enabled != expected.enabled {
+            Some(format!(
+                "expected acls to be {}, but they are {}",
+                if expected.enabled {
+                    "enabled"
+                } else {
+                    "disabled"
+                },
+                if enabled { "enabled" } else { "disabled" }
+            ))
+        } else {
+            expected
+                .default_policy
+                .as_deref()
+                .filter(|want| enabled && policy != Some(*want))
+                .map(|want| {
+                    format!(
+                        "expected acl default policy {}, but it is {}",
+                        want,
+                        policy.unwrap_or("unknown")
+                    )
+                })
+        };
+        match mismatch {
+            Some(detail) => Outcome::Failed(NotReadyReason::Unsatisfied(detail)),
+            None => Outcome::Online,
+        }
+    });
+    // a mismatch is the only unsatisfied condition that fails
+    if let Outcome::Failed(NotReadyReason::Unsatisfied(detail)) = &evaluation.outcome {
+        evaluation.error = Some(Error::AclMismatch(detail.clone()));
+    }
+    evaluation
+}
+
+#[cfg(feature = "native-transport")]
+fn dns_recurse(name: &str, server: Option<&str>, client: &Client, timeout: Duration) -> Evaluation {
+    let server = server
+        .map(str::to_owned)
+        .unwrap_or_else(|| format!("{}:8600", client.host()));
+    let (outcome, error) = match dns::query_a(&server, name, timeout) {
+        Ok(answer) if answer.rcode == 0 && answer.answers > 0 => (Outcome::Online, None),
+        Ok(answer) => (
+            unsatisfied(format!(
+                "{} could not resolve {}: {}",
+                server,
+                name,
+                answer.rcode_name()
+            )),
+            None,
+        ),
+        Err(e) if e.kind() == std::io::ErrorKind::ConnectionRefused => (
+            Outcome::Failed(NotReadyReason::ConnectionRefused {
+                addr: server.clone(),
+            }),
+            Some(Error::DnsQuery(e)),
+        ),
+        Err(e)
+            if matches!(
+                e.kind(),
+                std::io::ErrorKind::TimedOut | std::io::ErrorKind::WouldBlock
+            ) =>
+        {
+            (
+                Outcome::Failed(NotReadyReason::Timeout),
+                Some(Error::DnsQuery(e)),
+            )
+        }
+        Err(e) => (
+            Outcome::Failed(NotReadyReason::Transport(e.to_string())),
+            Some(Error::DnsQuery(e)),
+        ),
+    };
+    Evaluation {
+        target: format!("dns://{}/{}", server, name),
+        reply: None,
+        error,
+        outcome,
+    }
+}
+
+/// Without the native transport there is no way to send dns queries
+#[cfg(not(feature = "native-transport"))]
+fn dns_recurse(name: &str, server: Option<&str>, client: &Client, _: Duration) -> Evaluation {
+    let server = server
+        .map(str::to_owned)
+        .unwrap_or_else(|| format!("{}:8600", client.host()));
+    let e = std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        "dns queries need the native-transport feature",
+    );
+    Evaluation {
+        target: format!("dns://{}/{}", server, name),
+        reply: None,
+        outcome: Outcome::Failed(NotReadyReason::Transport(e.to_string())),
+        error: Some(Error::DnsQuery(e)),
+    }
+}
+
+/// Id of the sidecar proxy of `service` in a response of /v1/agent/services
+fn sidecar_proxy(body: &str, service: &str) -> Option<String> {
+    json(body)
+        .as_object()
+        .into_iter()
+        .flat_map(|services| services.values())
+        .find(|s| s["Kind"] == "connect-proxy" && s["Proxy"]["DestinationServiceName"] == service)
+        .and_then(|s| s["ID"].as_str().map(str::to_owned))
+}
+
+fn xds_service(service: &str, client: &Client, timeout: Duration) -> Evaluation {
+    let services =
+        client.evaluate(
+            "/v1/agent/services",
+            timeout,
+            &|response: &Response| match sidecar_proxy(response.body, service) {
+                Some(_) => Outcome::Online,
+                None => unsatisfied(format!("no sidecar proxy registered for {}", service)),
+            },
+        );
+    let proxy = match (&services.outcome, &services.reply) {
+        (Outcome::Online, Some(reply)) => sidecar_proxy(&reply.body, service),
+        _ => None,
+    };
+    let proxy = match proxy {
+        Some(proxy) => proxy,
+        None => return services,
+    };
+
+    // the agent health endpoint responds with 429 for warning and 503 for critical services
+    let path = format!("/v1/agent/health/service/id/{}", proxy);
+    client.evaluate(
+        &path,
+        timeout,
+        &Accept::new(&[200, 429, 503], |response: &Response| {
+            match json(response.body)["AggregatedStatus"].as_str() {
+                Some("passing") => Outcome::Online,
+                status => unsatisfied(format!(
+                    "sidecar proxy {} is {}",
+                    proxy,
+                    status.unwrap_or("unknown")
+                )),
+            }
+        }),
+    )
+}
@@ -0,0 +1,711 @@
+use std::io;
+use std::net::IpAddr;
+use std::net::Ipv4Addr;
+use std::net::Ipv6Addr;
+use std::net::SocketAddr;
+use std::net::UdpSocket;
+use std::time::Duration;
+use std::time::Instant;
+use std::time::SystemTime;
+
+use crate::probe::timed_out;
+use crate::probe::Resolver;
+
+/// Response code, number of answers and the A or AAAA records of a dns response
+pub(crate) struct DnsAnswer {
+    pub rcode: u8,
+    pub answers: u16,
+    pub addresses: Vec<IpAddr>,
+}
+
+impl DnsAnswer {
+    pub fn rcode_name(&self) -> &'static str {
+        rcode_name(self.rcode)
+    }
+}
+
+fn rcode_name(rcode: u8) -> &'static str {
+    match rcode {
+        0 => "NOERROR",
+        1 => "FORMERR",
+        2 => "SERVFAIL",
+        3 => "NXDOMAIN",
+        4 => "NOTIMP",
+        5 => "REFUSED",
+        _ => "UNKNOWN",
+    }
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// Target of an SRV record, with the addresses of the target from the additional section of the response
+#[derive(Debug, Clone)]
+pub(crate) struct SrvTarget {
+    pub priority: u16,
+    pub weight: u16,
+    pub port: u16,
+    pub target: String,
+    pub addresses: Vec<Ipv4Addr>,
+}
+
+const TYPE_A: u16 = 1;
+const TYPE_AAAA: u16 = 28;
+const TYPE_SRV: u16 = 33;
+
+/// Send a recursive A query for `name` to `server` over udp
+pub(crate) fn query_a(server: &str, name: &str, timeout: Duration) -> io::Result<DnsAnswer> {
+    query_addresses(server, name, TYPE_A, timeout)
+}
+
+/// Send a recursive A or AAAA query for `name` to `server` over udp
+fn query_addresses(
+    server: &str,
+    name: &str,
+    qtype: u16,
+    timeout: Duration,
+) -> io::Result<DnsAnswer> {
+    let (msg, answers) = query(server, name, qtype, timeout)?;
+    Ok(DnsAnswer {
+        rcode: msg[3] & 0x0f,
+        answers,
+        addresses: address_records(&msg, answers)
+            .ok_or_else(|| invalid("malformed dns response"))?,
+    })
+}
+
+/// Send a recursive SRV query for `name` to `server` over udp, an empty list if there are no records
+pub(crate) fn query_srv(server: &str, name: &str, timeout: Duration) -> io::Result<Vec<SrvTarget>> {
+    let (msg, _) = query(server, name, TYPE_SRV, timeout)?;
+    match msg[3] & 0x0f {
+        0 | 3 => srv_records(&msg).ok_or_else(|| invalid("malformed dns response")),
+        rcode => Err(io::Error::other(format!(
+            "srv query for {} failed: {}",
+            name,
+            rcode_name(rcode)
+        ))),
+    }
+}
+
+/// Order SRV targets as RFC 2782 says: by priority, and randomly by weight within the same priority
+pub(crate) fn srv_order(mut targets: Vec<SrvTarget>) -> Vec<SrvTarget> {
+    targets.sort_by_key(|t| t.priority);
+    let mut ordered = Vec::with_capacity(targets.len());
+    while !targets.is_empty() {
+        let priority = targets[0].priority;
+        let same = targets
+            .iter()
+            .take_while(|t| t.priority == priority)
+            .count();
+        let mut group: Vec<SrvTarget> = targets.drain(..same).collect();
+        while !group.is_empty() {
+            // targets with weight 0 only have a small chance to be chosen first
+            let total: u64 = group.iter().map(|t| t.weight as u64 + 1).sum();
+            let mut pick = crate::random() % total;
+            let index = group
+                .iter()
+                .position(|t| match pick.checked_sub(t.weight as u64 + 1) {
+                    Some(rest) => {
+                        pick = rest;
+                        false
+                    }
+                    None => true,
+                })
+                .unwrap_or(0);
+            ordered.push(group.remove(index));
+        }
+    }
+    ordered
+}
+
+/// First nameserver in /etc/resolv.conf as "host:port"
+pub(crate) fn system_nameserver() -> Option<String> {
+    ResolvConf::read().nameservers.into_iter().next()
+}
+
+/// The parts of /etc/resolv.conf the [InternalResolver] follows
+struct ResolvConf {
+    /// As "host:port", in order
+    nameservers: Vec<String>,
+    /// Domains appended to names, from the last search or domain line
+    search: Vec<String>,
+    /// Names with fewer dots are tried with the search domains first
+    ndots: usize,
+}
+
+impl ResolvConf {
+    fn read() -> Self {
+        Self::parse(&std::fs::read_to_string("/etc/resolv.conf").unwrap_or_default())
+    }
+
+    fn parse(contents: &str) -> Self {
+        let mut conf = ResolvConf {
+            nameservers: Vec::new(),
+            search: Vec::new(),
+            ndots: 1,
+        };
+        for line in contents.lines() {
+            let mut fields = line
+                .split(['#', ';'])
+                .next()
+                .unwrap_or_default()
+                .split_whitespace();
+            match fields.next() {
+                Some("nameserver") => conf.nameservers.extend(
+                    fields
+                        .next()
+                        .and_then(|server| server.parse::<IpAddr>().ok())
+                        .map(|ip| SocketAddr::new(ip, 53).to_string()),
+                ),
+                Some("search" | "domain") => {
+                    conf.search = fields.map(|d| d.trim_end_matches('.').to_owned()).collect()
+                }
+                Some("options") => {
+                    for option in fields {
+                        if let Some(Ok(n)) = option.strip_prefix("ndots:").map(str::parse::<usize>)
+                        {
+                            // same limit as glibc
+                            conf.ndots = n.min(15);
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+        conf
+    }
+
+    /// Names to look up for `host` in order, like the resolver of glibc: a name with at least ndots dots as it
+    /// is first, others with the search domains first. A name ending in a dot is never searched
+    fn candidates(&self, host: &str) -> Vec<String> {
+        if host.ends_with('.') {
+            return vec![host.to_owned()];
+        }
+        let searched = self
+            .search
+            .iter()
+            .map(|domain| format!("{}.{}", host, domain));
+        match host.matches('.').count() >= self.ndots {
+            true => std::iter::once(host.to_owned()).chain(searched).collect(),
+            false => searched.chain(std::iter::once(host.to_owned())).collect(),
+        }
+    }
+}
+
+/// Addresses of `host` in /etc/hosts
+fn hosts_file(host: &str) -> Vec<IpAddr> {
+    std::fs::read_to_string("/etc/hosts")
+        .unwrap_or_default()
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.split('#').next()?.split_whitespace();
+            let ip = fields.next()?.parse::<IpAddr>().ok()?;
+            fields
+                .any(|name| name.eq_ignore_ascii_case(host))
+                .then_some(ip)
+        })
+        .collect()
+}
+
+/// Split "host:port" into the host without brackets and the port
+fn split_netloc(netloc: &str) -> io::Result<(&str, u16)> {
+    netloc
+        .rsplit_once(':')
+        .and_then(|(host, port)| Some((host, port.parse::<u16>().ok()?)))
+        .map(|(host, port)| (host.trim_start_matches('[').trim_end_matches(']'), port))
+        .ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("invalid address: {}", netloc),
+            )
+        })
+}
+
+/// Send a recursive query of `qtype` for `name`, returns the response and its number of answers
+fn query(server: &str, name: &str, qtype: u16, timeout: Duration) -> io::Result<(Vec<u8>, u16)> {
+    let id = (SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .subsec_nanos()
+        ^ std::process::id()) as u16;
+
+    // header: id, flags (recursion desired), 1 question, 0 answer/authority/additional records
+    let mut query = Vec::with_capacity(32 + name.len());
+    query.extend_from_slice(&id.to_be_bytes());
+    query.extend_from_slice(&[0x01, 0x00, 0x00, 0x01, 0, 0, 0, 0, 0, 0]);
+    for label in name.trim_end_matches('.').split('.') {
+        if label.is_empty() || label.len() > 63 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("invalid dns name: {}", name),
+            ));
+        }
+        query.push(label.len() as u8);
+        query.extend_from_slice(label.as_bytes());
+    }
+    // root label, type, class IN
+    query.push(0);
+    query.extend_from_slice(&qtype.to_be_bytes());
+    query.extend_from_slice(&[0x00, 0x01]);
+
+    let socket = UdpSocket::bind(if server.starts_with('[') {
+        "[::]:0"
+    } else {
+        "0.0.0.0:0"
+    })?;
+    socket.set_read_timeout(Some(timeout.max(Duration::from_millis(1))))?;
+    socket.connect(server)?;
+    socket.send(&query)?;
+
+    let mut buf = [0u8; 512];
+    loop {
+        let len = socket.recv(&mut buf)?;
+        if len < 12 {
+            return Err(invalid("short dns response"));
+        }
+        if buf[0..2] != id.to_be_bytes() {
+            log::debug!("ignore dns response with unexpected id");
+            continue;
+        }
+        if buf[2] & 0x80 == 0 {
+            return Err(invalid("dns response is not a response"));
+        }
+        return Ok((buf[..len].to_vec(), u16::from_be_bytes([buf[6], buf[7]])));
+    }
+}
+
+/// Offset behind the (possibly compressed) name starting at `pos`
+fn skip_name(msg: &[u8], mut pos: usize) -> Option<usize> {
+    loop {
+        match *msg.get(pos)? {
+            0 => return Some(pos + 1),
+            len if len & 0xc0 == 0xc0 => return Some(pos + 2),
+            len => pos += 1 + len as usize,
+        }
+    }
+}
+
+/// Addresses of the A and AAAA records in the answer section of a response to a single question
+fn address_records(msg: &[u8], answers: u16) -> Option<Vec<IpAddr>> {
+    // skip the header and the question: name, type and class
+    let mut pos = skip_name(msg, 12)? + 4;
+    let mut addresses = Vec::new();
+    for _ in 0..answers {
+        pos = skip_name(msg, pos)?;
+        let record = msg.get(pos..pos + 10)?;
+        let len = u16::from_be_bytes([record[8], record[9]]) as usize;
+        let data = msg.get(pos + 10..pos + 10 + len)?;
+        let rtype = u16::from_be_bytes([record[0], record[1]]);
+        // class IN
+        match (rtype, record[2..4] == [0x00, 0x01], len) {
+            (TYPE_A, true, 4) => addresses.push(IpAddr::V4(Ipv4Addr::new(
+                data[0], data[1], data[2], data[3],
+            ))),
+            (TYPE_AAAA, true, 16) => {
+                let octets: [u8; 16] = data.try_into().ok()?;
+                addresses.push(IpAddr::V6(Ipv6Addr::from(octets)))
+            }
+            _ => {}
+        }
+        pos += 10 + len;
+    }
+    Some(addresses)
+}
+
+/// Name starting at `pos`, following compression pointers
+fn read_name(msg: &[u8], mut pos: usize) -> Option<String> {
+    let mut labels = Vec::new();
+    // a pointer loop would never end
+    for _ in 0..128 {
+        match *msg.get(pos)? {
+            0 => return Some(labels.join(".")),
+            len if len & 0xc0 == 0xc0 => {
+                pos = ((len as usize & 0x3f) << 8) | *msg.get(pos + 1)? as usize
+            }
+            len => {
+                let label = msg.get(pos + 1..pos + 1 + len as usize)?;
+                labels.push(String::from_utf8_lossy(label).to_lowercase());
+                pos += 1 + len as usize;
+            }
+        }
+    }
+    None
+}
+
+/// SRV records of the answer section, with the A records of the additional section for their targets
+fn srv_records(msg: &[u8]) -> Option<Vec<SrvTarget>> {
+    let count = |i: usize| Some(u16::from_be_bytes(msg.get(i..i + 2)?.try_into().ok()?) as usize);
+    let (answers, authority, additional) = (count(6)?, count(8)?, count(10)?);
+    let mut pos = skip_name(msg, 12)? + 4;
+    let mut targets = Vec::new();
+    let mut addresses = Vec::new();
+    for i in 0..answers + authority + additional {
+        let name = read_name(msg, pos)?;
+        pos = skip_name(msg, pos)?;
+        let record = msg.get(pos..pos + 10)?;
+        let rtype = u16::from_be_bytes([record[0], record[1]]);
+        let len = u16::from_be_bytes([record[8], record[9]]) as usize;
+        let data = msg.get(pos + 10..pos + 10 + len)?;
+        match rtype {
+            TYPE_SRV if i < answers && len > 6 => targets.push(SrvTarget {
+                priority: u16::from_be_bytes([data[0], data[1]]),
+                weight: u16::from_be_bytes([data[2], data[3]]),
+                port: u16::from_be_bytes([data[4], data[5]]),
+                target: read_name(msg, pos + 16)?,
+                addresses: Vec::new(),
+            }),
+            TYPE_A if i >= answers + authority && len == 4 => {
+                addresses.push((name, Ipv4Addr::new(data[0], data[1], data[2], data[3])))
+            }
+            _ => {}
+        }
+        pos += 10 + len;
+    }
+    for target in targets.iter_mut() {
+        target.addresses = addresses
+            .iter()
+            .filter(|(name, _)| *name == target.target)
+            .map(|(_, ip)| *ip)
+            .collect();
+    }
+    Some(targets)
+}
+
+/// Resolves by sending A queries to a specific nameserver, bypassing /etc/resolv.conf. AAAA queries follow for names
+/// without A records
+pub(crate) struct Nameserver {
+    server: String,
+    timeout: Duration,
+}
+
+impl Nameserver {
+    /// `server` is "host:port", the port defaults to 53
+    pub fn new(server: &str, timeout: Duration) -> Self {
+        let server = match server.parse::<SocketAddr>() {
+            Ok(addr) => addr.to_string(),
+            Err(_) if server.parse::<IpAddr>().is_ok() => {
+                SocketAddr::new(server.parse().unwrap(), 53).to_string()
+            }
+            Err(_) if server.contains(':') => server.to_owned(),
+            Err(_) => format!("{}:53", server),
+        };
+        Self { server, timeout }
+    }
+}
+
+impl Nameserver {
+    /// Answer to the A query for `host`, or to the AAAA query if the name has no A records. Both queries are
+    /// answered before `deadline` or fail with a timeout of `netloc`
+    fn answer(&self, host: &str, netloc: &str, deadline: Instant) -> io::Result<DnsAnswer> {
+        let query = |qtype| {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                return Err(timed_out(netloc, self.timeout));
+            }
+            query_addresses(&self.server, host, qtype, remaining).map_err(|e| {
+                match matches!(
+                    e.kind(),
+                    io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock
+                ) {
+                    true => timed_out(netloc, self.timeout),
+                    false => e,
+                }
+            })
+        };
+        let mut answer = query(TYPE_A)?;
+        // a name that does not exist has no AAAA records either
+        if answer.addresses.is_empty() && answer.rcode == 0 {
+            answer = query(TYPE_AAAA)?;
+        }
+        log::debug!(
+            "{} resolved {} to {:?} ({})",
+            self.server,
+            host,
+            answer.addresses,
+            answer.rcode_name()
+        );
+        Ok(answer)
+    }
+
+    /// Error for an `answer` without addresses
+    fn unresolved(&self, host: &str, answer: &DnsAnswer) -> io::Error {
+        io::Error::new(
+            io::ErrorKind::NotFound,
+            format!(
+                "{} could not resolve {}: {}",
+                self.server,
+                host,
+                answer.rcode_name()
+            ),
+        )
+    }
+}
+
+impl Resolver for Nameserver {
+    fn resolve(&self, netloc: &str) -> io::Result<Vec<SocketAddr>> {
+        let (host, port) = split_netloc(netloc)?;
+        if let Ok(ip) = host.parse::<IpAddr>() {
+            return Ok(vec![SocketAddr::new(ip, port)]);
+        }
+        let answer = self.answer(host, netloc, Instant::now() + self.timeout)?;
+        if answer.addresses.is_empty() {
+            return Err(self.unresolved(host, &answer));
+        }
+        Ok(answer
+            .addresses
+            .into_iter()
+            .map(|ip| SocketAddr::new(ip, port))
+            .collect())
+    }
+}
+
+/// Resolves without the resolver of the operating system and its name service switch: /etc/hosts first, then
+/// queries to the nameservers in /etc/resolv.conf. Names are tried with the search domains and ndots of
+/// /etc/resolv.conf like glibc does, the other options are ignored. Like glibc, a name that does not exist or has no
+/// addresses moves on to the next search domain, only a timeout or another failure of a nameserver moves on to the
+/// next nameserver. The whole lookup gives up after the timeout, each nameserver gets an equal share of it
+pub(crate) struct InternalResolver {
+    timeout: Duration,
+}
+
+impl InternalResolver {
+    pub fn new(timeout: Duration) -> Self {
+        Self { timeout }
+    }
+}
+
+impl Resolver for InternalResolver {
+    fn resolve(&self, netloc: &str) -> io::Result<Vec<SocketAddr>> {
+        let (host, port) = split_netloc(netloc)?;
+        if let Ok(ip) = host.parse::<IpAddr>() {
+            return Ok(vec![SocketAddr::new(ip, port)]);
+        }
+        let hosts = hosts_file(host);
+        if !hosts.is_empty() {
+            log::debug!("/etc/hosts resolved {} to {:?}", host, hosts);
+            return Ok(hosts
+                .into_iter()
+                .map(|ip| SocketAddr::new(ip, port))
+                .collect());
+        }
+        if host.eq_ignore_ascii_case("localhost") {
+            return Ok(vec![SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), port)]);
+        }
+        let conf = ResolvConf::read();
+        let mut last_error = io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("no nameserver in /etc/resolv.conf to resolve {}", host),
+        );
+        let deadline = Instant::now() + self.timeout;
+        // a stuck nameserver leaves time for the others
+        let share = self.timeout / conf.nameservers.len().max(1) as u32;
+        for name in conf.candidates(host) {
+            for server in &conf.nameservers {
+                if Instant::now() >= deadline {
+                    return Err(timed_out(netloc, self.timeout));
+                }
+                let nameserver = Nameserver::new(server, self.timeout);
+                match nameserver.answer(&name, netloc, deadline.min(Instant::now() + share)) {
+                    Ok(answer) if !answer.addresses.is_empty() => {
+                        return Ok(answer
+                            .addresses
+                            .into_iter()
+                            .map(|ip| SocketAddr::new(ip, port))
+                            .collect())
+                    }
+                    // NOERROR without addresses or NXDOMAIN: the other nameservers would answer the same
+                    Ok(answer) if matches!(answer.rcode, 0 | 3) => {
+                        last_error = nameserver.unresolved(&name, &answer);
+                        log::debug!("{}", last_error);
+                        break;
+                    }
+                    Ok(answer) => {
+                        last_error = nameserver.unresolved(&name, &answer);
+                        log::debug!("{}", last_error);
+                    }
+                    Err(e) => {
+                        log::debug!("{}", e);
+                        last_error = e;
+                    }
+                }
+            }
+        }
+        Err(last_error)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn conf(contents: &str) -> ResolvConf {
+        ResolvConf::parse(contents)
+    }
+
+    #[test]
+    fn resolv_conf_parse() {
+        let conf = conf(
+            "# generated\n\
+             nameserver 10.0.0.2\n\
+             nameserver fd00::53 ; second\n\
+             nameserver not-an-address\n\
+             domain corp.example\n\
+             search svc.cluster.local. cluster.local\n\
+             options timeout:2 ndots:5\n",
+        );
+        assert_eq!(conf.nameservers, ["10.0.0.2:53", "[fd00::53]:53"]);
+        // the last search or domain line wins, trailing dots are dropped
+        assert_eq!(conf.search, ["svc.cluster.local", "cluster.local"]);
+        assert_eq!(conf.ndots, 5);
+    }
+
+    #[test]
+    fn resolv_conf_defaults_and_limits() {
+        let empty = conf("");
+        assert!(empty.nameservers.is_empty() && empty.search.is_empty());
+        assert_eq!(empty.ndots, 1);
+        assert_eq!(conf("options ndots:30").ndots, 15);
+        assert_eq!(
+            conf("search a.example\ndomain b.example").search,
+            ["b.example"]
+        );
+    }
+
+    #[test]
+    fn candidates_follow_ndots_and_search_order() {
+        let conf = conf("search a.example b.example\noptions ndots:2");
+        assert_eq!(
+            conf.candidates("consul"),
+            ["consul.a.example", "consul.b.example", "consul"]
+        );
+        assert_eq!(
+            conf.candidates("consul.service"),
+            [
+                "consul.service.a.example",
+                "consul.service.b.example",
+                "consul.service"
+            ]
+        );
+        assert_eq!(
+            conf.candidates("consul.service.dc1"),
+            [
+                "consul.service.dc1",
+                "consul.service.dc1.a.example",
+                "consul.service.dc1.b.example"
+            ]
+        );
+    }
+
+    #[test]
+    fn candidates_of_a_name_with_a_trailing_dot() {
+        let conf = conf("search a.example");
+        assert_eq!(conf.candidates("consul.example."), ["consul.example."]);
+    }
+
+    /// A response with a single question for `name` of `qtype`, followed by `records`
+    fn response(name: &str, qtype: u16, counts: [u16; 3], records: &[u8]) -> Vec<u8> {
+        let mut msg = vec![0x12, 0x34, 0x81, 0x80, 0, 1];
+        for count in counts {
+            msg.extend_from_slice(&count.to_be_bytes());
+        }
+        for label in name.split('.') {
+            msg.push(label.len() as u8);
+            msg.extend_from_slice(label.as_bytes());
+        }
+        msg.push(0);
+        msg.extend_from_slice(&qtype.to_be_bytes());
+        msg.extend_from_slice(&[0, 1]);
+        msg.extend_from_slice(records);
+        msg
+    }
+
+    /// A record of `rtype` in class IN with a ttl of 60, `name` is encoded already
+    fn record(name: &[u8], rtype: u16, data: &[u8]) -> Vec<u8> {
+        let mut record = name.to_vec();
+        record.extend_from_slice(&rtype.to_be_bytes());
+        record.extend_from_slice(&[0, 1, 0, 0, 0, 60]);
+        record.extend_from_slice(&(data.len() as u16).to_be_bytes());
+        record.extend_from_slice(data);
+        record
+    }
+
+    /// Compression pointer to the name of the question
+    const QUESTION: [u8; 2] = [0xc0, 12];
+
+    #[test]
+    fn address_records_of_compressed_answers() {
+        let v6 = "fd00::1".parse::<Ipv6Addr>().unwrap().octets();
+        let records = [
+            // a CNAME is skipped
+            record(&QUESTION, 5, &[4, b'n', b'o', b'd', b'e', 0xc0, 12]),
+            record(&QUESTION, TYPE_A, &[10, 0, 0, 1]),
+            record(&QUESTION, TYPE_AAAA, &v6),
+        ]
+        .concat();
+        let msg = response("consul.example", TYPE_A, [3, 0, 0], &records);
+        assert_eq!(
+            address_records(&msg, 3).unwrap(),
+            [
+                IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)),
+                IpAddr::V6(Ipv6Addr::from(v6))
+            ]
+        );
+    }
+
+    #[test]
+    fn address_records_of_truncated_messages() {
+        let records = record(&QUESTION, TYPE_A, &[10, 0, 0, 1]);
+        let msg = response("consul.example", TYPE_A, [1, 0, 0], &records);
+        for len in [12, 20, msg.len() - 11, msg.len() - 1] {
+            assert!(address_records(&msg[..len], 1).is_none(), "length {}", len);
+        }
+        assert!(address_records(&msg, 2).is_none());
+    }
+
+    /// An SRV response for _consul._tcp.example.com with a target node1.example.com on port 8500, that points into
+    /// the question, and its address in the additional section, that points into the answer
+    fn srv_response() -> Vec<u8> {
+        // "_consul" and "_tcp" come first in the question, "example.com" starts behind them
+        let example = 12 + 8 + 5;
+        let mut srv = vec![0, 10, 0, 5, 0x21, 0x34];
+        srv.extend_from_slice(&[5, b'n', b'o', b'd', b'e', b'1', 0xc0, example]);
+        let answer = record(&QUESTION, TYPE_SRV, &srv);
+        let question_len = response("_consul._tcp.example.com", TYPE_SRV, [0; 3], &[]).len();
+        // the target name starts behind the name, type, class, ttl and length of the record and the priority,
+        // weight and port of the srv data
+        let target = (question_len + 2 + 10 + 6) as u8;
+        let additional = record(&[0xc0, target], TYPE_A, &[10, 0, 0, 7]);
+        response(
+            "_consul._tcp.example.com",
+            TYPE_SRV,
+            [1, 0, 1],
+            &[answer, additional].concat(),
+        )
+    }
+
+    #[test]
+    fn srv_records_with_compressed_targets() {
+        let targets = srv_records(&srv_response()).unwrap();
+        assert_eq!(targets.len(), 1);
+        let target = &targets[0];
+        assert_eq!((target.priority, target.weight, target.port), (10, 5, 8500));
+        assert_eq!(target.target, "node1.example.com");
+        assert_eq!(target.addresses, [Ipv4Addr::new(10, 0, 0, 7)]);
+    }
+
+    #[test]
+    fn srv_records_of_truncated_messages() {
+        let msg = srv_response();
+        for len in [0, 8, 12, 30, msg.len() - 1] {
+            assert!(srv_records(&msg[..len]).is_none(), "length {}", len);
+        }
+    }
+
+    /// Two pointers behind the question, pointing at each other
+    #[test]
+    fn read_name_stops_at_pointer_loops() {
+        let msg = response("consul", TYPE_A, [0; 3], &[0xc0, 26, 0xc0, 24]);
+        assert!(read_name(&msg, 24).is_none());
+    }
+}
@@ -0,0 +1,331 @@
+use std::fmt::Display;
+use std::net::SocketAddr;
+use std::time::Duration;
+use std::time::SystemTime;
+
+use crate::checks::Check;
+use crate::Error;
+
+/// Why the agent is not online (yet)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum NotReadyReason {
+    /// The cluster has not elected a leader
+    NoLeader,
+    /// The leader changed recently
+    LeaderUnstable {
+        leader: String,
+        stable_for: Duration,
+    },
+    /// The catalog index changed recently
+    CatalogUnsettled { index: u64, stable_for: Duration },
+    /// ACLs are enabled, but the ACL system was not bootstrapped yet
+    AclNotBootstrapped,
+    /// The acl token lacks a permission the check needs, which one if the agent names it, like `operator:read`
+    PermissionDenied { permission: Option<String> },
+    /// Nothing is listening at the agent address
+    ConnectionRefused { addr: String },
+    /// The agent did not respond in time
+    Timeout,
+    /// The agent address could not be resolved
+    Dns(String),
+    /// Resolving the agent address did not finish in time
+    DnsTimeout { name: String, after: Duration },
+    /// The condition of a check is not met yet
+    Unsatisfied(String),
+    /// The agent responded, but not within the maximum latency for enough consecutive polls
+    Slow {
+        latency: Duration,
+        max: Duration,
+        /// Consecutive polls within the maximum latency so far
+        fast: u32,
+        polls: u32,
+    },
+    /// The agent responded with an unexpected status code
+    Status { code: u16, body_excerpt: String },
+    /// Something other than http answered at the agent address
+    NotHttp { addr: String },
+    /// The agent speaks https (`tls`) or plain http, not the scheme of the request
+    WrongScheme { addr: String, tls: bool },
+    /// Any other failure while talking to the agent
+    Transport(String),
+}
+
+impl NotReadyReason {
+    /// Short, stable identifier for this kind of reason
+    pub fn kind(&self) -> &'static str {
+        match self {
+            NotReadyReason::NoLeader => "no-leader",
+            NotReadyReason::LeaderUnstable { .. } => "leader-unstable",
+            NotReadyReason::CatalogUnsettled { .. } => "catalog-unsettled",
+            NotReadyReason::AclNotBootstrapped => "acl-not-bootstrapped",
+            NotReadyReason::PermissionDenied { .. } => "permission-denied",
+            NotReadyReason::ConnectionRefused { .. } => "connection-refused",
+            NotReadyReason::Timeout => "timeout",
+            NotReadyReason::Dns(_) => "dns",
+            NotReadyReason::DnsTimeout { .. } => "dns-timeout",
+            NotReadyReason::Unsatisfied(_) => "unsatisfied",
+            NotReadyReason::Slow { .. } => "slow",
+            NotReadyReason::Status { .. } => "status",
+            NotReadyReason::NotHttp { .. } => "not-http",
+            NotReadyReason::WrongScheme { .. } => "wrong-scheme",
+            NotReadyReason::Transport(_) => "transport",
+        }
+    }
+}
+
+impl Display for NotReadyReason {
+    #[rustfmt::skip]
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            NotReadyReason::NoLeader => write!(f, "no cluster leader"),
+            NotReadyReason::LeaderUnstable { leader, stable_for } => write!(f, "leader {} only stable for {}s", leader, stable_for.as_secs()),
+            NotReadyReason::CatalogUnsettled { index, stable_for } => write!(f, "catalog index {} only stable for {}s", index, stable_for.as_secs()),
+            NotReadyReason::AclNotBootstrapped => write!(f, "acl system not bootstrapped"),
+            NotReadyReason::PermissionDenied { permission: Some(p) } => write!(f, "the acl token lacks permission {}", p),
+            NotReadyReason::PermissionDenied { permission: None } => write!(f, "permission denied, the acl token lacks a permission the check needs"),
+            NotReadyReason::ConnectionRefused { addr } => write!(f, "connection to {} refused", addr),
+            NotReadyReason::Timeout => write!(f, "timed out"),
+            NotReadyReason::Dns(e) => write!(f, "dns lookup failed: {}", e),
+            NotReadyReason::DnsTimeout { name, after } => write!(f, "resolving {} timed out after {}", name, humantime::format_duration(*after)),
+            NotReadyReason::Unsatisfied(detail) => write!(f, "{}", detail),
+            NotReadyReason::Slow { latency, max, fast: 0, .. } => write!(f, "responded in {}ms, more than the maximum of {}ms", latency.as_millis(), max.as_millis()),
+            NotReadyReason::Slow { max, fast, polls, .. } => write!(f, "responded within {}ms for {} of {} consecutive polls", max.as_millis(), fast, polls),
+            NotReadyReason::Status { code, body_excerpt } => write!(f, "status {}: {}", code, body_excerpt),
+            NotReadyReason::NotHttp { addr } => write!(f, "{} does not speak http", addr),
+            NotReadyReason::WrongScheme { addr, tls: true } => write!(f, "{} speaks https, not http", addr),
+            NotReadyReason::WrongScheme { addr, tls: false } => write!(f, "{} speaks plain http, not https", addr),
+            NotReadyReason::Transport(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+/// Result of a single request against the consul agent
+#[derive(Debug)]
+pub enum Outcome {
+    /// The agent is online
+    Online,
+    /// The agent responded, but is not online yet
+    NotReady(NotReadyReason),
+    /// The request failed
+    Failed(NotReadyReason),
+}
+
+impl Outcome {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Outcome::Online => "online",
+            Outcome::NotReady(_) => "not-ready",
+            Outcome::Failed(_) => "failed",
+        }
+    }
+
+    pub fn reason(&self) -> Option<&NotReadyReason> {
+        match self {
+            Outcome::Online => None,
+            Outcome::NotReady(r) | Outcome::Failed(r) => Some(r),
+        }
+    }
+}
+
+/// A single polling attempt
+#[derive(Debug)]
+pub struct Attempt<'a> {
+    /// Attempt counter, starting at 1. Every check is evaluated once per attempt
+    pub number: u64,
+    /// The check that was evaluated
+    pub check: &'a Check,
+    /// Time at which the request was started
+    pub timestamp: SystemTime,
+    /// Url that was requested
+    pub target: &'a str,
+    /// Time it took to complete the request
+    pub latency: Duration,
+    /// Http status code, if the agent responded
+    pub status: Option<u16>,
+    /// Response headers, empty if the agent did not respond
+    pub headers: &'a [(String, String)],
+    /// Response body, if the agent responded
+    pub body: Option<&'a str>,
+    /// Address of the raft leader, if one was reported
+    pub leader: Option<&'a str>,
+    /// Number of leader changes observed so far
+    pub leader_changes: u32,
+    /// Address the request was sent to, if known
+    pub peer: Option<SocketAddr>,
+    pub outcome: Outcome,
+}
+
+/// Distinct reasons why attempts were not online, with how often each was seen
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ReasonHistory(Vec<ReasonCount>);
+
+/// A reason of a [ReasonHistory]. Reasons are distinct by check and [NotReadyReason::kind]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReasonCount {
+    pub check: String,
+    pub kind: &'static str,
+    /// The most recent message for this reason
+    pub last: String,
+    pub count: u64,
+}
+
+impl ReasonHistory {
+    pub fn record(&mut self, attempt: &Attempt) {
+        let reason = match attempt.outcome.reason() {
+            Some(reason) => reason,
+            None => return,
+        };
+        let check = attempt.check.to_string();
+        match self
+            .0
+            .iter_mut()
+            .find(|r| r.kind == reason.kind() && r.check == check)
+        {
+            Some(seen) => {
+                seen.count += 1;
+                seen.last = reason.to_string();
+            }
+            None => self.0.push(ReasonCount {
+                check,
+                kind: reason.kind(),
+                last: reason.to_string(),
+                count: 1,
+            }),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn reasons(&self) -> &[ReasonCount] {
+        &self.0
+    }
+
+    pub fn to_json(&self) -> serde_json::Value {
+        self.0
+            .iter()
+            .map(|r| {
+                serde_json::json!({
+                    "check": r.check,
+                    "reason": r.kind,
+                    "last": r.last,
+                    "count": r.count,
+                })
+            })
+            .collect()
+    }
+}
+
+impl Display for ReasonHistory {
+    /// Like "raft: no cluster leader (12x), raft: connection to 10.0.0.1:8500 refused (3x)"
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (i, r) in self.0.iter().enumerate() {
+            if i > 0 {
+                f.write_str(", ")?;
+            }
+            write!(f, "{}: {} ({}x)", r.check, r.last, r.count)?;
+        }
+        Ok(())
+    }
+}
+
+/// Details about the cluster, collected once the agent is online
+#[derive(Debug, Clone, Default)]
+pub struct AgentInfo {
+    /// Address of the raft leader
+    pub leader: Option<String>,
+    /// Consul version of the agent
+    pub version: Option<String>,
+    /// Address of the agent that answered
+    pub address: Option<String>,
+}
+
+/// Whether the checks pass, as tracked by [crate::watch]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum State {
+    Online,
+    Offline,
+}
+
+impl State {
+    pub fn name(&self) -> &'static str {
+        match self {
+            State::Online => "online",
+            State::Offline => "offline",
+        }
+    }
+}
+
+impl Display for State {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// A change of the [State] while watching
+#[derive(Debug)]
+pub struct Transition<'a> {
+    /// The state before, None for the first state after starting to watch
+    pub from: Option<State>,
+    pub to: State,
+    /// Why the agent is offline, as "<check>: <reason>"
+    pub reason: Option<&'a str>,
+}
+
+/// Receives events while waiting for consul to come online
+pub trait Observer {
+    /// Called after every attempt
+    fn attempt(&mut self, _attempt: &Attempt) {}
+
+    /// Called once the agent is online, before [Observer::finished]
+    fn online(&mut self, _info: &AgentInfo) {}
+
+    /// Called exactly once when the wait is over
+    fn finished(&mut self, _result: &std::result::Result<(), Error>) {}
+
+    /// Called when the state changes while watching
+    fn changed(&mut self, _transition: &Transition) {}
+}
+
+impl Observer for () {}
+
+impl<O: Observer + ?Sized> Observer for Vec<Box<O>> {
+    fn attempt(&mut self, attempt: &Attempt) {
+        self.iter_mut().for_each(|o| o.attempt(attempt))
+    }
+
+    fn online(&mut self, info: &AgentInfo) {
+        self.iter_mut().for_each(|o| o.online(info))
+    }
+
+    fn finished(&mut self, result: &std::result::Result<(), Error>) {
+        self.iter_mut().for_each(|o| o.finished(result))
+    }
+
+    fn changed(&mut self, transition: &Transition) {
+        self.iter_mut().for_each(|o| o.changed(transition))
+    }
+}
+
+impl<A: Observer + ?Sized, B: Observer + ?Sized> Observer for (&mut A, &mut B) {
+    fn attempt(&mut self, attempt: &Attempt) {
+        self.0.attempt(attempt);
+        self.1.attempt(attempt);
+    }
+
+    fn online(&mut self, info: &AgentInfo) {
+        self.0.online(info);
+        self.1.online(info);
+    }
+
+    fn finished(&mut self, result: &std::result::Result<(), Error>) {
+        self.0.finished(result);
+        self.1.finished(result);
+    }
+
+    fn changed(&mut self, transition: &Transition) {
+        self.0.changed(transition);
+        self.1.changed(transition);
+    }
+}
@@ -0,0 +1,42 @@
+use crate::Config;
+use crate::Error;
+use crate::PollOutcome;
+use crate::Result;
+use crate::Waiter;
+
+/// Same as [crate::wait], but awaits instead of blocking the calling thread. Needs a tokio runtime.
+/// The pauses between the rounds are tokio sleeps, the requests of a round run with `spawn_blocking`, through
+/// [Config::transport] if there is one. Dropping the future stops the wait: a pause ends right away, a round in flight
+/// finishes on the blocking pool and its result is discarded
+pub async fn wait_async(config: Config) -> Result<()> {
+    let mut waiter = blocking(move || Waiter::new(config)).await??;
+    loop {
+        let (returned, outcome) = blocking(move || {
+            let outcome = waiter.poll_once();
+            (waiter, outcome)
+        })
+        .await?;
+        waiter = returned;
+        match outcome {
+            PollOutcome::Online(_) => return Ok(()),
+            PollOutcome::Failed(e) => return Err(e),
+            PollOutcome::Pending { retry_in } => {
+                log::debug!("sleep {} millis", retry_in.as_millis());
+                tokio::time::sleep(retry_in).await;
+            }
+        }
+    }
+}
+
+/// Run `f` on the blocking pool of the runtime, a panic in `f` is resumed in the caller
+async fn blocking<T, F>(f: F) -> Result<T>
+where
+    T: Send + 'static,
+    F: FnOnce() -> T + Send + 'static,
+{
+    match tokio::task::spawn_blocking(f).await {
+        Ok(value) => Ok(value),
+        Err(e) if e.is_panic() => std::panic::resume_unwind(e.into_panic()),
+        Err(e) => Err(Error::General(format!("blocking task failed: {}", e))),
+    }
+}
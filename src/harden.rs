@@ -0,0 +1,331 @@
+//! Least privilege for the rest of the run, see [Config::hardened](crate::Config::hardened)
+//!
+//! On linux, a seccomp filter makes system calls that write to the filesystem, execute programs or change privileges
+//! fail with EPERM. Files that are already open can still be written, files can still be opened for reading and
+//! network connections work as before.
+
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+static HARDENED: AtomicBool = AtomicBool::new(false);
+
+/// Install the filter for this process and all of its threads. Does nothing if the process is hardened already
+pub(crate) fn harden() -> io::Result<()> {
+    if HARDENED.load(Ordering::Relaxed) {
+        return Ok(());
+    }
+    install()?;
+    HARDENED.store(true, Ordering::Relaxed);
+    Ok(())
+}
+
+#[cfg(all(
+    target_os = "linux",
+    any(target_arch = "x86_64", target_arch = "aarch64")
+))]
+fn install() -> io::Result<()> {
+    let mut filter = filter();
+    let program = libc::sock_fprog {
+        len: filter.len() as u16,
+        filter: filter.as_mut_ptr(),
+    };
+    // SAFETY: the program points to the filter, which outlives both calls, the kernel copies it
+    unsafe {
+        if libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        if libc::syscall(
+            libc::SYS_seccomp,
+            libc::SECCOMP_SET_MODE_FILTER,
+            libc::SECCOMP_FILTER_FLAG_TSYNC,
+            &program as *const libc::sock_fprog,
+        ) != 0
+        {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+#[cfg(not(all(
+    target_os = "linux",
+    any(target_arch = "x86_64", target_arch = "aarch64")
+)))]
+fn install() -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "hardening is only supported on linux on x86_64 and aarch64",
+    ))
+}
+
+#[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+const AUDIT_ARCH: u32 = 0xc000_003e;
+#[cfg(all(target_os = "linux", target_arch = "aarch64"))]
+const AUDIT_ARCH: u32 = 0xc000_00b7;
+
+/// Always fail with EPERM
+#[cfg(all(
+    target_os = "linux",
+    any(target_arch = "x86_64", target_arch = "aarch64")
+))]
+const DENIED: &[libc::c_long] = &[
+    libc::SYS_unlinkat,
+    libc::SYS_renameat,
+    libc::SYS_renameat2,
+    libc::SYS_mkdirat,
+    libc::SYS_mknodat,
+    libc::SYS_linkat,
+    libc::SYS_symlinkat,
+    libc::SYS_fchmod,
+    libc::SYS_fchmodat,
+    libc::SYS_fchown,
+    libc::SYS_fchownat,
+    libc::SYS_truncate,
+    libc::SYS_ftruncate,
+    libc::SYS_execve,
+    libc::SYS_execveat,
+    libc::SYS_ptrace,
+    libc::SYS_process_vm_writev,
+    libc::SYS_mount,
+    libc::SYS_umount2,
+    libc::SYS_pivot_root,
+    libc::SYS_chroot,
+    libc::SYS_setuid,
+    libc::SYS_setgid,
+    libc::SYS_setreuid,
+    libc::SYS_setregid,
+    libc::SYS_setresuid,
+    libc::SYS_setresgid,
+    libc::SYS_setgroups,
+    libc::SYS_init_module,
+    libc::SYS_finit_module,
+    libc::SYS_delete_module,
+    libc::SYS_kexec_load,
+    libc::SYS_reboot,
+    libc::SYS_swapon,
+    libc::SYS_swapoff,
+    libc::SYS_bpf,
+    // the submissions of an io_uring open files without the filter seeing their flags
+    libc::SYS_io_uring_setup,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_creat,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_unlink,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_rename,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_mkdir,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_rmdir,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_mknod,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_link,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_symlink,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_chmod,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_chown,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_lchown,
+];
+
+/// Not in libc for every target, the same number on x86_64 and aarch64
+#[cfg(all(
+    target_os = "linux",
+    any(target_arch = "x86_64", target_arch = "aarch64")
+))]
+const SYS_OPENAT2: libc::c_long = 437;
+
+/// Opening a file with any of these flags fails with EPERM
+#[cfg(all(
+    target_os = "linux",
+    any(target_arch = "x86_64", target_arch = "aarch64")
+))]
+const WRITE_FLAGS: u32 = (libc::O_WRONLY | libc::O_RDWR | libc::O_CREAT | libc::O_TRUNC) as u32;
+
+/// Where a conditional jump of the filter goes
+#[cfg(all(
+    target_os = "linux",
+    any(target_arch = "x86_64", target_arch = "aarch64")
+))]
+#[derive(Clone, Copy)]
+enum To {
+    Next,
+    Skip(u8),
+    Allow,
+    Deny,
+}
+
+#[cfg(all(
+    target_os = "linux",
+    any(target_arch = "x86_64", target_arch = "aarch64")
+))]
+fn filter() -> Vec<libc::sock_filter> {
+    use libc::{BPF_ABS, BPF_JEQ, BPF_JMP, BPF_JSET, BPF_K, BPF_LD, BPF_RET, BPF_W};
+    const LD: u16 = (BPF_LD | BPF_W | BPF_ABS) as u16;
+    const RET: u16 = (BPF_RET | BPF_K) as u16;
+    const JEQ: u16 = (BPF_JMP | BPF_JEQ | BPF_K) as u16;
+    const JSET: u16 = (BPF_JMP | BPF_JSET | BPF_K) as u16;
+    // offsets into struct seccomp_data: nr, arch, then the 64 bit arguments from 16 on
+    const NR: u32 = 0;
+    const ARCH: u32 = 4;
+    let arg = |n: u32| 16 + 8 * n + if cfg!(target_endian = "big") { 4 } else { 0 };
+
+    let mut code: Vec<(u16, u32, To, To)> = vec![
+        (LD, ARCH, To::Next, To::Next),
+        (JEQ, AUDIT_ARCH, To::Skip(1), To::Next),
+        (RET, libc::SECCOMP_RET_KILL_PROCESS, To::Next, To::Next),
+        (LD, NR, To::Next, To::Next),
+    ];
+    #[cfg(target_arch = "x86_64")]
+    code.push((JSET, 0x4000_0000, To::Deny, To::Next));
+    for nr in DENIED {
+        code.push((JEQ, *nr as u32, To::Deny, To::Next));
+    }
+    let mut opens = vec![(libc::SYS_openat, 2)];
+    #[cfg(target_arch = "x86_64")]
+    opens.push((libc::SYS_open, 1));
+    for (nr, flags) in opens {
+        code.push((JEQ, nr as u32, To::Next, To::Skip(2)));
+        code.push((LD, arg(flags), To::Next, To::Next));
+        code.push((JSET, WRITE_FLAGS, To::Deny, To::Allow));
+    }
+    // openat2 takes its flags in a struct the filter can't see, fail it so that callers fall back to openat
+    code.push((JEQ, SYS_OPENAT2 as u32, To::Next, To::Allow));
+    code.push((
+        RET,
+        libc::SECCOMP_RET_ERRNO | libc::ENOSYS as u32,
+        To::Next,
+        To::Next,
+    ));
+
+    let allow = code.len();
+    let deny = allow + 1;
+    let mut filter: Vec<libc::sock_filter> = code
+        .into_iter()
+        .enumerate()
+        .map(|(i, (op, k, jt, jf))| {
+            let offset = |to: To| match to {
+                To::Next => 0,
+                To::Skip(n) => n,
+                To::Allow => (allow - i - 1) as u8,
+                To::Deny => (deny - i - 1) as u8,
+            };
+            libc::sock_filter {
+                code: op,
+                jt: offset(jt),
+                jf: offset(jf),
+                k,
+            }
+        })
+        .collect();
+    for k in [
+        libc::SECCOMP_RET_ALLOW,
+        libc::SECCOMP_RET_ERRNO | libc::EPERM as u32,
+    ] {
+        filter.push(libc::sock_filter {
+            code: RET,
+            jt: 0,
+            jf: 0,
+            k,
+        });
+    }
+    filter
+}
+
+#[cfg(all(
+    test,
+    target_os = "linux",
+    any(target_arch = "x86_64", target_arch = "aarch64")
+))]
+mod tests {
+    use super::*;
+
+    /// Run the filter for a system call like the kernel does, returns the action
+    fn run(nr: libc::c_long, args: [u64; 6]) -> u32 {
+        let mut data = Vec::with_capacity(64);
+        data.extend_from_slice(&(nr as u32).to_ne_bytes());
+        data.extend_from_slice(&AUDIT_ARCH.to_ne_bytes());
+        data.extend_from_slice(&0u64.to_ne_bytes());
+        for arg in args {
+            data.extend_from_slice(&arg.to_ne_bytes());
+        }
+        let filter = filter();
+        let (mut pc, mut acc) = (0, 0u32);
+        loop {
+            let insn = &filter[pc];
+            let class = insn.code as u32 & 0x07;
+            match class {
+                libc::BPF_LD => {
+                    let at = insn.k as usize;
+                    acc = u32::from_ne_bytes(data[at..at + 4].try_into().unwrap());
+                    pc += 1;
+                }
+                libc::BPF_JMP => {
+                    let taken = match insn.code as u32 & 0xf0 {
+                        libc::BPF_JEQ => acc == insn.k,
+                        libc::BPF_JSET => acc & insn.k != 0,
+                        op => panic!("unexpected jump {:#x}", op),
+                    };
+                    pc += 1 + if taken { insn.jt } else { insn.jf } as usize;
+                }
+                libc::BPF_RET => return insn.k,
+                _ => panic!("unexpected instruction {:#x}", insn.code),
+            }
+        }
+    }
+
+    const EPERM: u32 = libc::SECCOMP_RET_ERRNO | libc::EPERM as u32;
+    const ENOSYS: u32 = libc::SECCOMP_RET_ERRNO | libc::ENOSYS as u32;
+
+    fn flags(flags: libc::c_int) -> u64 {
+        flags as u64
+    }
+
+    #[test]
+    fn openat2_fails_with_enosys() {
+        let how = flags(libc::O_WRONLY | libc::O_CREAT | libc::O_TRUNC);
+        assert_eq!(run(SYS_OPENAT2, [0, 0, how, 24, 0, 0]), ENOSYS);
+    }
+
+    #[test]
+    fn openat_for_writing_is_denied() {
+        for write in [
+            libc::O_WRONLY,
+            libc::O_RDWR,
+            libc::O_CREAT,
+            libc::O_WRONLY | libc::O_CREAT | libc::O_TRUNC,
+        ] {
+            assert_eq!(run(libc::SYS_openat, [0, 0, flags(write), 0, 0, 0]), EPERM);
+        }
+    }
+
+    #[test]
+    fn openat_for_reading_is_allowed() {
+        let read = flags(libc::O_RDONLY | libc::O_CLOEXEC);
+        assert_eq!(
+            run(libc::SYS_openat, [0, 0, read, 0, 0, 0]),
+            libc::SECCOMP_RET_ALLOW
+        );
+    }
+
+    #[test]
+    fn denied_calls_fail_with_eperm() {
+        for nr in [
+            libc::SYS_io_uring_setup,
+            libc::SYS_unlinkat,
+            libc::SYS_execve,
+        ] {
+            assert_eq!(run(nr, [0; 6]), EPERM);
+        }
+    }
+
+    #[test]
+    fn other_calls_are_allowed() {
+        for nr in [libc::SYS_read, libc::SYS_write, libc::SYS_connect] {
+            assert_eq!(run(nr, [0; 6]), libc::SECCOMP_RET_ALLOW);
+        }
+    }
+}
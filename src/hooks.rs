@@ -0,0 +1,170 @@
+use std::process::Command;
+use std::process::Stdio;
+use std::time::Instant;
+
+use crate::events::Attempt;
+use crate::events::Observer;
+use crate::events::Transition;
+use crate::Error;
+
+fn shell(cmd: &str) -> Command {
+    if cfg!(windows) {
+        let mut c = Command::new("cmd");
+        c.arg("/C").arg(cmd);
+        c
+    } else {
+        let mut c = Command::new("sh");
+        c.arg("-c").arg(cmd);
+        c
+    }
+}
+
+/// Run a shell command, the outcome of the command is only logged.
+/// Hook output is redirected to stderr, to keep stdout clean for the result.
+fn run(cmd: &str, env: &[(&str, String)]) {
+    log::debug!("run hook: {}", cmd);
+    match shell(cmd)
+        .envs(env.iter().map(|(k, v)| (k, v.as_str())))
+        .stdout(Stdio::from(std::io::stderr()))
+        .status()
+    {
+        Ok(status) if status.success() => {}
+        Ok(status) => log::warn!("hook '{}' failed: {}", cmd, status),
+        Err(e) => log::warn!("failed to run hook '{}': {}", cmd, e),
+    }
+}
+
+fn elapsed(start: Instant) -> String {
+    start.elapsed().as_secs().to_string()
+}
+
+/// Runs a command after every attempt
+pub struct AttemptHook {
+    cmd: String,
+    start: Instant,
+}
+
+impl AttemptHook {
+    pub fn new(cmd: &str) -> Self {
+        Self {
+            cmd: cmd.to_owned(),
+            start: Instant::now(),
+        }
+    }
+}
+
+impl Observer for AttemptHook {
+    fn attempt(&mut self, attempt: &Attempt) {
+        run(
+            self.cmd.as_str(),
+            &[
+                ("CONSUL_ONLINE_STATUS", attempt.outcome.name().to_owned()),
+                ("CONSUL_ONLINE_ATTEMPT", attempt.number.to_string()),
+                ("CONSUL_ONLINE_CHECK", attempt.check.to_string()),
+                ("CONSUL_ONLINE_ELAPSED", elapsed(self.start)),
+                (
+                    "CONSUL_ONLINE_REASON",
+                    attempt
+                        .outcome
+                        .reason()
+                        .map(|r| r.kind().to_owned())
+                        .unwrap_or_default(),
+                ),
+                (
+                    "CONSUL_ONLINE_HTTP_STATUS",
+                    attempt.status.map(|s| s.to_string()).unwrap_or_default(),
+                ),
+                ("CONSUL_ONLINE_TARGET", attempt.target.to_owned()),
+                (
+                    "CONSUL_ONLINE_PEER",
+                    attempt.peer.map(|p| p.to_string()).unwrap_or_default(),
+                ),
+            ],
+        )
+    }
+}
+
+/// Runs a command once the wait is over, depending on whether consul came online or not
+pub struct TerminalHook {
+    on_ready: Option<String>,
+    on_fail: Option<String>,
+    start: Instant,
+    attempts: u64,
+}
+
+impl TerminalHook {
+    pub fn new(on_ready: Option<&str>, on_fail: Option<&str>) -> Self {
+        Self {
+            on_ready: on_ready.map(str::to_owned),
+            on_fail: on_fail.map(str::to_owned),
+            start: Instant::now(),
+            attempts: 0,
+        }
+    }
+}
+
+impl Observer for TerminalHook {
+    fn attempt(&mut self, attempt: &Attempt) {
+        self.attempts = attempt.number;
+    }
+
+    fn finished(&mut self, result: &std::result::Result<(), Error>) {
+        let (cmd, status, error) = match result {
+            Ok(_) => (&self.on_ready, "online", String::new()),
+            Err(e @ Error::Timeout(..)) => (&self.on_fail, "timeout", e.to_string()),
+            Err(e @ Error::Degraded(_)) => (&self.on_fail, "degraded", e.to_string()),
+            Err(e) => (&self.on_fail, "failed", e.to_string()),
+        };
+        if let Some(cmd) = cmd {
+            run(
+                cmd.as_str(),
+                &[
+                    ("CONSUL_ONLINE_STATUS", status.to_owned()),
+                    ("CONSUL_ONLINE_ATTEMPTS", self.attempts.to_string()),
+                    ("CONSUL_ONLINE_ELAPSED", elapsed(self.start)),
+                    ("CONSUL_ONLINE_ERROR", error),
+                ],
+            )
+        }
+    }
+}
+
+/// Runs a command whenever the state changes while watching
+pub struct ChangeHook {
+    cmd: String,
+    since: Instant,
+}
+
+impl ChangeHook {
+    pub fn new(cmd: &str) -> Self {
+        Self {
+            cmd: cmd.to_owned(),
+            since: Instant::now(),
+        }
+    }
+}
+
+impl Observer for ChangeHook {
+    fn changed(&mut self, transition: &Transition) {
+        run(
+            self.cmd.as_str(),
+            &[
+                ("CONSUL_ONLINE_STATUS", transition.to.name().to_owned()),
+                (
+                    "CONSUL_ONLINE_PREVIOUS_STATUS",
+                    transition
+                        .from
+                        .map(|s| s.name())
+                        .unwrap_or_default()
+                        .to_owned(),
+                ),
+                (
+                    "CONSUL_ONLINE_REASON",
+                    transition.reason.unwrap_or_default().to_owned(),
+                ),
+                ("CONSUL_ONLINE_ELAPSED", elapsed(self.since)),
+            ],
+        );
+        self.since = Instant::now();
+    }
+}
@@ -0,0 +1,150 @@
+//! Deciding whether a response of the agent means that a check passes
+//!
+//! Every check requests an endpoint of the agent and hands the response to a [ResponseInterpreter]. Responses with a
+//! status the interpreter does not accept are judged by their status alone, e.g. a 500 without a cluster leader is
+//! not ready yet and a 403 is a missing permission. Programs using the library can add checks of their own with
+//! [crate::checks::Check::Custom] and any of the interpreters here, or one of their own. Closures taking a
+//! [Response] are interpreters, too.
+
+use std::marker::PhantomData;
+
+use serde::de::DeserializeOwned;
+
+use crate::events::NotReadyReason;
+use crate::events::Outcome;
+
+/// A response of the agent with a status the [ResponseInterpreter] accepts
+#[derive(Debug, Clone, Copy)]
+pub struct Response<'a> {
+    pub status: u16,
+    /// Value of the X-Consul-Index header
+    pub index: Option<u64>,
+    pub headers: &'a [(String, String)],
+    pub body: &'a str,
+}
+
+impl Response<'_> {
+    /// Value of the first header with this name, ignoring case
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+}
+
+/// Decides whether a response of the agent means that a check passes
+pub trait ResponseInterpreter: Send + Sync {
+    /// Status codes of the responses to interpret
+    fn statuses(&self) -> &[u16] {
+        &[200]
+    }
+
+    fn interpret(&self, response: &Response) -> Outcome;
+}
+
+impl<F> ResponseInterpreter for F
+where
+    F: Fn(&Response) -> Outcome + Send + Sync,
+{
+    fn interpret(&self, response: &Response) -> Outcome {
+        self(response)
+    }
+}
+
+/// The check passes once the agent responds with an accepted status, whatever the body
+#[derive(Debug, Clone, Copy, Default)]
+pub struct StatusOnly;
+
+impl ResponseInterpreter for StatusOnly {
+    fn interpret(&self, _: &Response) -> Outcome {
+        Outcome::Online
+    }
+}
+
+/// Decodes the body as json into `T` and decides with a function of the value. A body that does not fit `T` is not
+/// ready yet
+pub struct Json<T, F> {
+    decide: F,
+    target: PhantomData<fn() -> T>,
+}
+
+impl<T, F> Json<T, F>
+where
+    T: DeserializeOwned,
+    F: Fn(&T) -> Outcome + Send + Sync,
+{
+    pub fn new(decide: F) -> Self {
+        Self {
+            decide,
+            target: PhantomData,
+        }
+    }
+}
+
+impl<T, F> ResponseInterpreter for Json<T, F>
+where
+    T: DeserializeOwned,
+    F: Fn(&T) -> Outcome + Send + Sync,
+{
+    fn interpret(&self, response: &Response) -> Outcome {
+        match serde_json::from_str::<T>(response.body) {
+            Ok(value) => (self.decide)(&value),
+            Err(e) => Outcome::NotReady(NotReadyReason::Unsatisfied(format!(
+                "unexpected response: {}",
+                e
+            ))),
+        }
+    }
+}
+
+/// The check passes once the body matches a regular expression
+#[cfg(feature = "regex")]
+#[derive(Debug, Clone)]
+pub struct Pattern(regex::Regex);
+
+#[cfg(feature = "regex")]
+impl Pattern {
+    pub fn new(pattern: &str) -> Result<Self, regex::Error> {
+        regex::Regex::new(pattern).map(Self)
+    }
+}
+
+#[cfg(feature = "regex")]
+impl ResponseInterpreter for Pattern {
+    fn interpret(&self, response: &Response) -> Outcome {
+        match self.0.is_match(response.body) {
+            true => Outcome::Online,
+            false => Outcome::NotReady(NotReadyReason::Unsatisfied(format!(
+                "response does not match {}",
+                self.0
+            ))),
+        }
+    }
+}
+
+/// Interprets responses with any of `statuses` with another interpreter, for endpoints that respond with a status
+/// other than 200 while the check does not pass, like 404 while a key does not exist
+pub struct Accept<I> {
+    statuses: &'static [u16],
+    interpreter: I,
+}
+
+impl<I: ResponseInterpreter> Accept<I> {
+    pub fn new(statuses: &'static [u16], interpreter: I) -> Self {
+        Self {
+            statuses,
+            interpreter,
+        }
+    }
+}
+
+impl<I: ResponseInterpreter> ResponseInterpreter for Accept<I> {
+    fn statuses(&self) -> &[u16] {
+        self.statuses
+    }
+
+    fn interpret(&self, response: &Response) -> Outcome {
+        self.interpreter.interpret(response)
+    }
+}
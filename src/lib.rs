@@ -1,32 +1,114 @@
-use rustls::client::HandshakeSignatureValid;
-use rustls::client::ServerCertVerified;
-use rustls::client::ServerCertVerifier;
+#![allow(clippy::result_large_err, clippy::large_enum_variant)]
+// the json! of Config::redacted_json has more keys than the default limit allows
+#![recursion_limit = "256"]
+
+pub use rustls;
+#[cfg(feature = "native-transport")]
+use rustls::client::danger::HandshakeSignatureValid;
+#[cfg(feature = "native-transport")]
+use rustls::client::danger::ServerCertVerified;
+use rustls::client::danger::ServerCertVerifier;
+#[cfg(feature = "native-transport")]
+use rustls::client::Resumption;
+#[cfg(feature = "native-transport")]
 use rustls::client::WantsClientCert;
-use rustls::client::WantsTransparencyPolicyOrClientCert;
-use rustls::Certificate;
+use rustls::crypto::CryptoProvider;
+use rustls::pki_types::CertificateDer;
+#[cfg(feature = "native-transport")]
+use rustls::pki_types::PrivateKeyDer;
+#[cfg(feature = "native-transport")]
+use rustls::pki_types::PrivatePkcs1KeyDer;
+#[cfg(feature = "native-transport")]
+use rustls::pki_types::PrivatePkcs8KeyDer;
+#[cfg(feature = "native-transport")]
+use rustls::pki_types::PrivateSec1KeyDer;
+#[cfg(feature = "native-transport")]
+use rustls::pki_types::ServerName;
+#[cfg(feature = "native-transport")]
+use rustls::pki_types::UnixTime;
+#[cfg(feature = "native-transport")]
 use rustls::ClientConfig;
+#[cfg(feature = "native-transport")]
 use rustls::ConfigBuilder;
-use rustls::OwnedTrustAnchor;
-use rustls::PrivateKey;
+#[cfg(feature = "native-transport")]
+use rustls::DigitallySignedStruct;
+#[cfg(feature = "native-transport")]
 use rustls::RootCertStore;
+#[cfg(feature = "native-transport")]
+use rustls::SignatureScheme;
+#[cfg(feature = "native-transport")]
 use rustls::WantsVerifier;
 use std::fmt::Display;
 use std::fs;
 use std::path::PathBuf;
+use std::str::FromStr;
 use std::sync::Arc;
 use std::time::Duration;
+use std::time::Instant;
 use std::time::SystemTime;
 use ureq::Agent;
-use ureq::Request;
+pub use ureq::Request;
+
+#[cfg(feature = "tokio")]
+pub use future::wait_async;
+
+pub mod audit;
+pub mod bundle;
+pub mod checks;
+#[cfg(feature = "native-transport")]
+mod dns;
+pub mod events;
+#[cfg(feature = "tokio")]
+mod future;
+mod harden;
+pub mod hooks;
+pub mod interpret;
+pub mod notify;
+pub mod policy;
+mod probe;
+pub mod progress;
+pub mod raft;
+pub mod report;
+mod state;
+pub mod status;
+#[cfg(any(unix, feature = "native-transport"))]
+mod transport;
+mod unix;
+
+use checks::AclExpectation;
+use checks::Check;
+use checks::CheckSpec;
+use checks::HeaderExpectation;
+use checks::IndexTracker;
+use checks::MinInstances;
+use checks::Phase;
+use checks::Profile;
+use checks::Severity;
+use events::AgentInfo;
+use events::Attempt;
+use events::NotReadyReason;
+use events::Observer;
+use events::Outcome;
+use events::ReasonHistory;
+use events::State;
+use events::Transition;
+use interpret::ResponseInterpreter;
+use policy::Quantifier;
+use policy::RetryPolicy;
+use policy::SuccessPolicy;
+use probe::PreferredAddress;
+use raft::LeaderTracker;
+use raft::RaftConfiguration;
 
 #[derive(Debug)]
 pub enum Error {
     General(String),
     UnixSocketUnsupported,
-    InvalidBool(String),
+    /// Name and value of an environment variable that is not a boolean
+    InvalidBool(String, String),
     ReadCaCert(std::io::Error),
     ParseCaCert(pem::PemError),
-    AddCaCert(webpki::Error),
+    AddCaCert(rustls::Error),
     AddClientCert(rustls::Error),
     MissingClientKey,
     MissingClientCert,
@@ -35,8 +117,49 @@ pub enum Error {
     ReadClientCert(std::io::Error),
     ParseClientCert(pem::PemError),
     ReadTokenFile(std::io::Error),
+    InvalidKeyringEntry(String),
+    ReadKeyring(String),
+    KeyringUnsupported,
+    OpenAuditLog(std::io::Error),
+    OpenProgress(std::io::Error),
+    /// [Config::forbid_insecure] is set and the configuration is insecure for this reason
+    Insecure(String),
+    /// Installing the restrictions of [Config::hardened] failed
+    Harden(std::io::Error),
     Request(ureq::Error),
-    Timeout(Duration),
+    /// Request over the unix socket at this path failed
+    UnixSocket(String, std::io::Error),
+    /// Request to this address over a connection from [Config::source_address] failed
+    Connection(String, std::io::Error),
+    /// Connections can't be made from this [Config::source_address]
+    SourceAddress(String, std::io::Error),
+    /// The agent address answered with something other than http: address and port
+    NotHttp(String, u16),
+    /// The agent at this address speaks the other scheme: https if true, plain http if false
+    WrongScheme(String, bool),
+    ReadResponse(std::io::Error),
+    DnsQuery(std::io::Error),
+    /// Resolving this name did not finish within [Config::dns_timeout]
+    DnsTimeout(String, Duration),
+    Status(u16, String),
+    /// The acl token lacks this permission, or one the agent did not name
+    PermissionDenied(Option<String>),
+    /// Time waited and the reasons attempts were not online
+    Timeout(Duration, ReasonHistory),
+    LeaderFlapping(u32),
+    AclMismatch(String),
+    InvalidFingerprint(String),
+    InvalidProxy(String),
+    /// With [Config::strict_config]
+    ConfigConflicts(Vec<ConfigConflict>),
+    BootstrapTrust(String),
+    /// All required checks pass, these checks with [checks::Severity::Degraded] do not
+    Degraded(Vec<String>),
+    OpenLockFile(std::io::Error),
+    /// Another process holds the lock on [Config::exclusive]: path and the pid written by that process
+    Locked(String, Option<u32>),
+    /// The wait failed in this [Phase]
+    Phase(String, Box<Error>),
 }
 
 impl Display for Error {
@@ -44,8 +167,8 @@ impl Display for Error {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             Error::General(err) => write!(f, "error: {}", err),
-            Error::UnixSocketUnsupported => write!(f, "unix sockets are not supported at the moment"),
-            Error::InvalidBool(v) => write!(f, "environment variable could not be parsed as boolean: {}", v),
+            Error::UnixSocketUnsupported => write!(f, "unix sockets are not supported on this platform"),
+            Error::InvalidBool(name, v) => write!(f, "environment variable {} could not be parsed as boolean: {}", name, v),
             Error::ReadCaCert(e) => write!(f, "could not read the ca certificate: {}", e),
             Error::ParseCaCert(e) => write!(f, "could not parse the provided ca certificate: {}", e),
             Error::AddCaCert(e) => write!(f, "invalid ca certificate: {}", e),
@@ -57,82 +180,1028 @@ impl Display for Error {
             Error::ReadClientCert(e) => write!(f, "failed to read client cert: {}", e),
             Error::ParseClientCert(e) => write!(f, "failed to parse client cert: {}", e),
             Error::ReadTokenFile(e) => write!(f, "failed to read token file: {}", e),
+            Error::InvalidKeyringEntry(e) => write!(f, "invalid keyring entry, expected <service>/<account>: {}", e),
+            Error::ReadKeyring(e) => write!(f, "failed to read token from keyring: {}", e),
+            Error::KeyringUnsupported => write!(f, "keyring support was not enabled at build time"),
+            Error::OpenAuditLog(e) => write!(f, "failed to open audit log: {}", e),
+            Error::OpenProgress(e) => write!(f, "failed to open progress file: {}", e),
+            Error::Insecure(reason) => write!(f, "refusing to run, insecure configuration: {}", reason),
+            Error::Harden(e) => write!(f, "failed to harden the process: {}", e),
             Error::Request(e) => write!(f, "request failed: {}", e),
-            Error::Timeout(d) => write!(f, "timed out after {} seconds", d.as_secs()),
+            Error::UnixSocket(path, e) => write!(f, "request over unix socket {} failed: {}", path, e),
+            Error::Connection(addr, e) => write!(f, "request to {} failed: {}", addr, e),
+            Error::SourceAddress(source, e) => write!(f, "can't connect from source address {}: {}", source, e),
+            Error::NotHttp(addr, port) => match consul_port(*port) {
+                Some(serves) => write!(f, "{} does not speak http: port {} is not the http api but consul's {} port, the http api listens on 8500 (8501 with tls) by default", addr, port, serves),
+                None => write!(f, "{} does not speak http, is it the address of the http api? consul serves it on port 8500 (8501 with tls) by default", addr),
+            },
+            Error::WrongScheme(addr, true) => write!(f, "{} speaks https, not http: use an https:// address or --tls", addr),
+            Error::WrongScheme(addr, false) => write!(f, "{} speaks plain http, not https: use an http:// address without --tls", addr),
+            Error::ReadResponse(e) => write!(f, "failed to read response: {}", e),
+            Error::DnsQuery(e) => write!(f, "dns query failed: {}", e),
+            Error::DnsTimeout(name, after) => write!(f, "resolving {} timed out after {}, is the nameserver reachable?", name, humantime::format_duration(*after)),
+            Error::Status(code, body) => write!(f, "request failed with status {}: {}", code, body),
+            Error::PermissionDenied(Some(p)) => write!(f, "permission denied: the acl token lacks permission {}, grant it in a policy of the token", p),
+            Error::PermissionDenied(None) => write!(f, "permission denied: the acl token lacks a permission the checks need"),
+            Error::Timeout(d, history) if history.is_empty() => write!(f, "timed out after {} seconds", d.as_secs()),
+            Error::Timeout(d, history) => write!(f, "timed out after {} seconds, observed: {}", d.as_secs(), history),
+            Error::LeaderFlapping(n) => write!(f, "raft leader changed {} times", n),
+            Error::AclMismatch(e) => write!(f, "unexpected acl configuration: {}", e),
+            Error::InvalidProxy(e) => write!(f, "invalid proxy: {}", e),
+            Error::ConfigConflicts(c) => write!(f, "conflicting configuration: {}", c.iter().map(|c| c.to_string()).collect::<Vec<_>>().join("; ")),
+            Error::InvalidFingerprint(e) => write!(f, "invalid certificate fingerprint, expected a sha256 hex digest: {}", e),
+            Error::BootstrapTrust(e) => write!(f, "failed to bootstrap trust from the connect ca roots: {}", e),
+            Error::Degraded(failing) => write!(f, "consul is degraded: {}", failing.join("; ")),
+            Error::OpenLockFile(e) => write!(f, "failed to open lock file: {}", e),
+            Error::Locked(path, Some(pid)) => write!(f, "another instance (pid {}) is already running, it holds the lock on {}", pid, path),
+            Error::Locked(path, None) => write!(f, "another instance is already running, it holds the lock on {}", path),
+            Error::Phase(name, e) => write!(f, "phase {}: {}", name, e),
         }
     }
 }
 
 type Result<T> = std::result::Result<T, Error>;
 
-#[derive(Debug)]
+/// Tls implementation and the crypto provider it uses
+#[cfg(all(feature = "native-transport", not(feature = "fips")))]
+pub const TLS_BACKEND: &str = "rustls 0.23, crypto provider: ring";
+/// Tls implementation and the crypto provider it uses
+#[cfg(feature = "fips")]
+pub const TLS_BACKEND: &str = "rustls 0.23, crypto provider: aws-lc-rs (fips)";
+/// Tls implementation and the crypto provider it uses
+#[cfg(not(feature = "native-transport"))]
+pub const TLS_BACKEND: &str = "none, tls is up to Config::transport";
+
+/// The crypto provider of [TLS_BACKEND]
+#[cfg(all(feature = "native-transport", not(feature = "fips")))]
+fn default_crypto_provider() -> Result<Arc<CryptoProvider>> {
+    Ok(Arc::new(rustls::crypto::ring::default_provider()))
+}
+
+/// The crypto provider of [TLS_BACKEND], with the FIPS-validated module of aws-lc
+#[cfg(feature = "fips")]
+fn default_crypto_provider() -> Result<Arc<CryptoProvider>> {
+    Ok(Arc::new(rustls::crypto::default_fips_provider()))
+}
+
+/// Default of [Config::dns_timeout], same as the per attempt timeout of the glibc resolver
+pub const DNS_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Process exit codes for each class of failure
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExitCodes {
+    pub init: i32,
+    pub timeout: i32,
+    pub request: i32,
+    pub flapping: i32,
+    pub acl: i32,
+    pub degraded: i32,
+    /// Another instance holds the lock of [Config::exclusive]
+    pub locked: i32,
+    /// The agent address does not speak http, like the serf or rpc port
+    pub protocol: i32,
+    /// A bug, the process panicked
+    pub internal: i32,
+}
+
+impl Default for ExitCodes {
+    fn default() -> Self {
+        Self {
+            init: 1,
+            timeout: 2,
+            request: 3,
+            flapping: 4,
+            acl: 5,
+            degraded: 6,
+            locked: 7,
+            protocol: 8,
+            internal: 70,
+        }
+    }
+}
+
+impl FromStr for ExitCodes {
+    type Err = String;
+
+    /// Parse a list of overrides like "timeout=75,request=69,init=64"
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        s.split(',')
+            .filter(|entry| !entry.trim().is_empty())
+            .try_fold(ExitCodes::default(), |mut codes, entry| {
+                let (name, code) = entry
+                    .split_once('=')
+                    .ok_or_else(|| format!("expected <class>=<code>, got: {}", entry))?;
+                // 0 would report a failed wait as success, and the shell only sees the low 8 bits of larger codes
+                let code = code
+                    .trim()
+                    .parse::<u8>()
+                    .ok()
+                    .filter(|code| *code != 0)
+                    .ok_or_else(|| {
+                        format!(
+                            "invalid exit code for {}: {}, expected 1 to 255",
+                            name.trim(),
+                            code.trim()
+                        )
+                    })? as i32;
+                match name.trim() {
+                    "init" => codes.init = code,
+                    "timeout" => codes.timeout = code,
+                    "request" => codes.request = code,
+                    "flapping" => codes.flapping = code,
+                    "acl" => codes.acl = code,
+                    "degraded" => codes.degraded = code,
+                    "locked" => codes.locked = code,
+                    "protocol" => codes.protocol = code,
+                    "internal" => codes.internal = code,
+                    other => return Err(format!("unknown exit code class: {}", other)),
+                }
+                Ok(codes)
+            })
+    }
+}
+
+impl ExitCodes {
+    /// Exit code for a failed wait
+    pub fn code(&self, err: &Error) -> i32 {
+        match err {
+            Error::Request(_)
+            | Error::UnixSocket(..)
+            | Error::Connection(..)
+            | Error::ReadResponse(_)
+            | Error::Status(..)
+            | Error::DnsQuery(_)
+            | Error::DnsTimeout(..)
+            | Error::BootstrapTrust(_) => self.request,
+            Error::LeaderFlapping(_) => self.flapping,
+            Error::AclMismatch(_) | Error::PermissionDenied(_) => self.acl,
+            Error::Degraded(_) => self.degraded,
+            Error::Locked(..) => self.locked,
+            Error::NotHttp(..) | Error::WrongScheme(..) => self.protocol,
+            Error::Timeout(..) => self.timeout,
+            Error::Phase(_, e) => self.code(e),
+            _ => self.init,
+        }
+    }
+}
+
+impl Error {
+    /// The error without the [Phase] it occurred in
+    pub fn without_phase(&self) -> &Error {
+        match self {
+            Error::Phase(_, e) => e.without_phase(),
+            e => e,
+        }
+    }
+
+    /// Exit code the cli uses for this error, with the default [ExitCodes]
+    pub fn exit_code(&self) -> i32 {
+        ExitCodes::default().code(self)
+    }
+
+    /// The error is in the `init` class of [ExitCodes]: the wait could not start
+    pub fn is_init(&self) -> bool {
+        // only the init class has a code other than 0, whatever codes a remap chose
+        let init_only = ExitCodes {
+            init: 1,
+            timeout: 0,
+            request: 0,
+            flapping: 0,
+            acl: 0,
+            degraded: 0,
+            locked: 0,
+            protocol: 0,
+            internal: 0,
+        };
+        init_only.code(self) == 1
+    }
+}
+
+#[derive(Clone)]
 pub struct Config {
     pub http_addr: String,
+    /// Further addresses of agents, tried in turn after `http_addr` every round. Whether the checks must pass on any
+    /// or all of them is up to the targets policy of [Config::success_policy]
+    pub fallback_addrs: Vec<String>,
     pub http_ssl: bool,
-    pub timeout: Option<u64>,
-    pub interval: Option<u64>,
+    /// Without a scheme in the address and without `http_ssl`, use https for the default https port of the agent
+    /// (8501) and http for its default http port (8500)
+    pub infer_scheme: bool,
+    /// If the agent turns out to speak the other scheme, see [Error::WrongScheme], switch to it once instead of failing
+    pub auto_scheme: bool,
+    /// Nameserver ("host:port") to resolve the agent address with instead of the system resolver.
+    /// Also used to look up the SRV records of a srv:// address, which otherwise come from the first nameserver
+    /// in /etc/resolv.conf
+    pub dns_server: Option<String>,
+    /// Resolve the agent address without the resolver of the operating system, which depends on the name service
+    /// switch of the c library: read /etc/hosts, then query the nameservers in /etc/resolv.conf directly
+    pub internal_resolver: bool,
+    /// Give up resolving the agent address or looking up SRV records after this long, separately from the
+    /// request timeout, which does not cover name resolution
+    pub dns_timeout: Duration,
+    /// Make connections to the agent from this ip address or network interface (linux only), for multi-homed hosts.
+    /// Connections from a source address don't follow redirects and don't go through proxies
+    pub source_address: Option<String>,
+    /// Give up after this long, never if None. Zero means: make exactly one attempt
+    pub timeout: Option<Duration>,
+    /// Time between the start of two rounds [default: 10 seconds]
+    pub interval: Option<Duration>,
+    /// Replaces `timeout`, `interval` and `leader_stable_for` if set, see [Config::retry_policy]
+    pub retry: Option<RetryPolicy>,
+    /// Which checks must pass on which targets for consul to be online. Only checks with
+    /// [checks::Severity::Required] count, degraded checks still have to pass as well
+    pub success_policy: SuccessPolicy,
     pub reconnect: bool,
+    /// Hold an advisory lock on this file while waiting, so only one instance waits at a time.
+    /// Fails with [Error::Locked] if another process holds it
+    pub exclusive: Option<String>,
+    /// Keep the start time and the number of rounds in this file while waiting, so that a restarted process resumes
+    /// the wait with the same deadline instead of starting over. Removed once the wait is over, ignored if it was
+    /// written for a different configuration
+    pub state_file: Option<String>,
+    /// Once the files are read and opened, forbid writing to the filesystem, executing programs and changing
+    /// privileges for the rest of the run (seccomp, linux only). Can't be combined with the hooks, the support bundle
+    /// or the token prompt
+    pub hardened: bool,
+    /// Conflicts between the sources the configuration was built from, like a flag and an environment variable.
+    /// Reported with those [Config::conflicts] finds in the configuration itself
+    pub source_conflicts: Vec<ConfigConflict>,
+    /// Fail with [Error::ConfigConflicts] instead of warning about conflicts
+    pub strict_config: bool,
+    /// Fail with [Error::Insecure] if certificate verification is skipped or an agent that is not on this host is
+    /// reached over plain http, see [Config::insecure]
+    pub forbid_insecure: bool,
     pub skip_verify: bool,
+    /// Trust the connect ca roots served by the agent, fetched over an unverified or pinned connection
+    pub trust_on_bootstrap: bool,
+    /// Sha256 fingerprint of the agent certificate to accept while fetching the connect ca roots
+    pub bootstrap_fingerprint: Option<String>,
     pub ca_cert: Option<String>,
+    /// Protocols offered with alpn during the tls handshake, none if empty
+    pub alpn_protocols: Vec<String>,
+    /// Resume tls sessions across attempts instead of making a full handshake every time
+    pub tls_resume: bool,
+    /// Read response bodies to the end, so connections are kept alive and reused between attempts.
+    /// Otherwise only what the checks need is read and every connection is closed after its request
+    pub drain_bodies: bool,
+    /// Log up to this many bytes of the response body of every failed attempt at debug level, none if 0
+    pub log_body_bytes: usize,
+    /// Use blocking queries for the checks whose endpoints support them, see [Check::supports_blocking]: the
+    /// agent holds each request until the result changes or the polling interval passed, instead of responding
+    /// right away and sleeping between requests
+    pub blocking_queries: bool,
+    /// Ignore the proxy environment variables, requests always go out directly
+    pub no_env: bool,
+    /// Crypto provider for tls connections, defaults to the one of [TLS_BACKEND]: ring, aws-lc-rs with the `fips` feature
+    pub crypto_provider: Option<Arc<CryptoProvider>>,
+    /// Verifies the agent certificate instead of the default root certificates and `ca_cert`
+    pub verifier: Option<Arc<dyn ServerCertVerifier>>,
     pub client_cert: Option<String>,
     pub client_key: Option<String>,
     pub http_token: Option<String>,
     pub http_token_file: Option<String>,
+    pub http_token_keyring: Option<String>,
+    /// Asked for a token once if the agent rejects requests because of acls and no token is configured
+    pub token_prompt: Option<Arc<dyn Fn() -> Option<String> + Send + Sync>>,
+    /// Keep trying to read the token file or keyring entry while waiting, instead of failing right away
+    pub retry_token: bool,
+    /// Only use endpoints that never need an acl token: the leader and peers checks of the status endpoints and the
+    /// dns check. Other checks and a configured token are errors, the default check is [Check::Leader]
+    pub no_token: bool,
+    /// Conditions to wait for, defaults to [Check::Raft] if empty and there is no profile.
+    /// Overrides the timeout and interval of the same check in the profile
+    pub checks: Vec<CheckSpec>,
+    /// Preset of checks, extended by `checks`
+    pub profile: Option<Profile>,
+    /// Parts of the wait, each with its own budget and the checks that name it, see [CheckSpec::phase]
+    pub phases: Vec<Phase>,
+    pub leader_stable_for: Option<Duration>,
+    /// Only count the raft check as online if a server of the raft configuration is flagged as the leader
+    pub require_leader: bool,
+    pub max_leader_changes: Option<u32>,
+    pub catalog_stable_for: Option<Duration>,
+    /// Headers every successful response must have, a check with a response missing one is not online
+    pub expect_headers: Vec<HeaderExpectation>,
+    /// Services that must have at least one passing instance, see [Check::Service]
+    pub services: Vec<String>,
+    /// Minimum numbers of passing instances of services, overrides those of the checks. Services that are not
+    /// checked yet are checked as well
+    pub min_instances: MinInstances,
+    /// Minimum number of servers in the raft configuration, see [Check::Peers]. Replaces the peers check of the profile
+    pub min_peers: Option<usize>,
+    /// Nodes that must be registered in the catalog, see [Check::Node]
+    pub nodes: Vec<String>,
+    /// Only count a check as online if its response took at most this long
+    pub max_latency: Option<Duration>,
+    /// Number of consecutive polls a check must respond within `max_latency`
+    pub max_latency_polls: u32,
+    pub expect_acl: Option<AclExpectation>,
+    pub fail_on_flapping: bool,
+    pub audit_log: Option<String>,
+    /// Write one json line per attempt to this file, or to stderr if it is [progress::STDERR]
+    pub progress: Option<String>,
+    /// Directory to write a support bundle to when the wait fails, see [bundle::SupportBundle]
+    pub support_bundle: Option<String>,
+    pub on_attempt: Option<String>,
+    pub on_ready: Option<String>,
+    pub on_fail: Option<String>,
+    /// Shell command to run when the state changes, only used by [watch]
+    pub on_change: Option<String>,
+    pub notify_url: Option<String>,
+    pub notify_token: Option<String>,
+    pub notify_retries: u32,
+    /// Applied to every request sent to the agent, after the token and authorization headers
+    pub decorators: Vec<Arc<dyn RequestDecorator>>,
+    /// Sends the requests to the agent instead of the built-in http client, e.g. an http client of the host
+    /// platform. The tls, proxy, source address and unix socket options don't apply to it
+    pub transport: Option<Arc<dyn Transport>>,
+}
+
+impl Default for Config {
+    /// Same defaults as the command line
+    fn default() -> Self {
+        Self {
+            http_addr: "localhost:8500".to_owned(),
+            fallback_addrs: Vec::new(),
+            http_ssl: false,
+            infer_scheme: true,
+            auto_scheme: false,
+            dns_server: None,
+            internal_resolver: false,
+            dns_timeout: DNS_TIMEOUT,
+            source_address: None,
+            timeout: None,
+            interval: None,
+            retry: None,
+            success_policy: SuccessPolicy::default(),
+            reconnect: false,
+            exclusive: None,
+            state_file: None,
+            hardened: false,
+            source_conflicts: Vec::new(),
+            strict_config: false,
+            forbid_insecure: false,
+            skip_verify: false,
+            trust_on_bootstrap: false,
+            bootstrap_fingerprint: None,
+            ca_cert: None,
+            alpn_protocols: Vec::new(),
+            tls_resume: true,
+            drain_bodies: true,
+            log_body_bytes: 0,
+            blocking_queries: false,
+            no_env: false,
+            crypto_provider: None,
+            verifier: None,
+            client_cert: None,
+            client_key: None,
+            http_token: None,
+            http_token_file: None,
+            http_token_keyring: None,
+            token_prompt: None,
+            retry_token: false,
+            no_token: false,
+            checks: Vec::new(),
+            phases: Vec::new(),
+            profile: None,
+            leader_stable_for: None,
+            require_leader: false,
+            max_leader_changes: None,
+            catalog_stable_for: None,
+            expect_headers: Vec::new(),
+            services: Vec::new(),
+            min_instances: MinInstances::default(),
+            min_peers: None,
+            nodes: Vec::new(),
+            max_latency: None,
+            max_latency_polls: 1,
+            expect_acl: None,
+            fail_on_flapping: false,
+            audit_log: None,
+            progress: None,
+            support_bundle: None,
+            on_attempt: None,
+            on_ready: None,
+            on_fail: None,
+            on_change: None,
+            notify_url: None,
+            notify_token: None,
+            notify_retries: 3,
+            decorators: Vec::new(),
+            transport: None,
+        }
+    }
+}
+
+impl std::fmt::Debug for Config {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Config")
+            .field("http_addr", &redact_address(&self.http_addr))
+            .field(
+                "fallback_addrs",
+                &self
+                    .fallback_addrs
+                    .iter()
+                    .map(|a| redact_address(a))
+                    .collect::<Vec<_>>(),
+            )
+            .field("http_ssl", &self.http_ssl)
+            .field("infer_scheme", &self.infer_scheme)
+            .field("auto_scheme", &self.auto_scheme)
+            .field("dns_server", &self.dns_server)
+            .field("internal_resolver", &self.internal_resolver)
+            .field("dns_timeout", &self.dns_timeout)
+            .field("source_address", &self.source_address)
+            .field("timeout", &self.timeout)
+            .field("interval", &self.interval)
+            .field("retry", &self.retry)
+            .field("success_policy", &self.success_policy)
+            .field("reconnect", &self.reconnect)
+            .field("exclusive", &self.exclusive)
+            .field("state_file", &self.state_file)
+            .field("hardened", &self.hardened)
+            .field("source_conflicts", &self.source_conflicts)
+            .field("strict_config", &self.strict_config)
+            .field("forbid_insecure", &self.forbid_insecure)
+            .field("skip_verify", &self.skip_verify)
+            .field("trust_on_bootstrap", &self.trust_on_bootstrap)
+            .field("bootstrap_fingerprint", &self.bootstrap_fingerprint)
+            .field("ca_cert", &self.ca_cert)
+            .field("alpn_protocols", &self.alpn_protocols)
+            .field("tls_resume", &self.tls_resume)
+            .field("drain_bodies", &self.drain_bodies)
+            .field("log_body_bytes", &self.log_body_bytes)
+            .field("blocking_queries", &self.blocking_queries)
+            .field("no_env", &self.no_env)
+            .field("crypto_provider", &self.crypto_provider.is_some())
+            .field("verifier", &self.verifier)
+            .field("client_cert", &self.client_cert)
+            .field("client_key", &self.client_key)
+            .field(
+                "http_token",
+                &self.http_token.as_ref().map(|_| "<redacted>"),
+            )
+            .field("http_token_file", &self.http_token_file)
+            .field("http_token_keyring", &self.http_token_keyring)
+            .field("token_prompt", &self.token_prompt.is_some())
+            .field("retry_token", &self.retry_token)
+            .field("no_token", &self.no_token)
+            .field("checks", &self.checks)
+            .field("phases", &self.phases)
+            .field("profile", &self.profile)
+            .field("leader_stable_for", &self.leader_stable_for)
+            .field("require_leader", &self.require_leader)
+            .field("max_leader_changes", &self.max_leader_changes)
+            .field("catalog_stable_for", &self.catalog_stable_for)
+            .field("expect_headers", &self.expect_headers)
+            .field("services", &self.services)
+            .field("min_instances", &self.min_instances)
+            .field("min_peers", &self.min_peers)
+            .field("nodes", &self.nodes)
+            .field("max_latency", &self.max_latency)
+            .field("max_latency_polls", &self.max_latency_polls)
+            .field("expect_acl", &self.expect_acl)
+            .field("fail_on_flapping", &self.fail_on_flapping)
+            .field("audit_log", &self.audit_log)
+            .field("progress", &self.progress)
+            .field("support_bundle", &self.support_bundle)
+            .field("on_attempt", &self.on_attempt)
+            .field("on_ready", &self.on_ready)
+            .field("on_fail", &self.on_fail)
+            .field("on_change", &self.on_change)
+            .field("notify_url", &self.notify_url)
+            .field(
+                "notify_token",
+                &self.notify_token.as_ref().map(|_| "<redacted>"),
+            )
+            .field("notify_retries", &self.notify_retries)
+            .field("decorators", &self.decorators.len())
+            .field("transport", &self.transport.is_some())
+            .finish()
+    }
+}
+
+impl Config {
+    /// Hash over the effective configuration. Secrets are not part of the hash, only their presence is.
+    pub fn redacted_hash(&self) -> String {
+        // 64 bit FNV-1a, stable across builds unlike std's DefaultHasher
+        let hash = format!("{:?}", self)
+            .bytes()
+            .fold(0xcbf29ce484222325u64, |h, b| {
+                (h ^ b as u64).wrapping_mul(0x100000001b3)
+            });
+        format!("{:016x}", hash)
+    }
+
+    /// When to poll and when to give up, [Config::retry] or the policy built from the loose fields
+    pub fn retry_policy(&self) -> RetryPolicy {
+        self.retry.clone().unwrap_or_else(|| RetryPolicy {
+            timeout: self.timeout,
+            stability_window: self.leader_stable_for,
+            ..RetryPolicy::fixed(self.interval.unwrap_or(Duration::from_secs(10)))
+        })
+    }
+
+    /// Options that contradict each other or have no effect. Each is resolved as its message says, unless
+    /// [Config::strict_config] makes them fatal
+    pub fn conflicts(&self) -> Vec<ConfigConflict> {
+        let mut conflicts = self.source_conflicts.clone();
+        if let Ok((_, _, found)) = url_base(self) {
+            conflicts.extend(found);
+        }
+        conflicts
+    }
+
+    /// Why the configuration is insecure: certificate verification is skipped, or one of the agents is reached over
+    /// plain http although it is not on a loopback address. Unix sockets count as local
+    pub fn insecure(&self) -> Option<String> {
+        if self.skip_verify {
+            return Some("certificate verification is skipped".to_owned());
+        }
+        std::iter::once(&self.http_addr)
+            .chain(&self.fallback_addrs)
+            .find_map(|addr| {
+                let target = Config {
+                    http_addr: addr.clone(),
+                    fallback_addrs: Vec::new(),
+                    ..self.clone()
+                };
+                match url_base(&target) {
+                    Ok((url, false, _)) if !addr.starts_with("unix:/") && !is_loopback(&url) => {
+                        Some(format!(
+                            "{} is reached over plain http",
+                            redact_address(&url)
+                        ))
+                    }
+                    _ => None,
+                }
+            })
+    }
+
+    /// Why the configuration can't be used with [Config::no_token]: a token is configured or a check needs one
+    fn token_required(&self) -> Option<String> {
+        if self.http_token.is_some()
+            || self.http_token_file.is_some()
+            || self.http_token_keyring.is_some()
+        {
+            return Some("--no-token can't be used with a token".to_owned());
+        }
+        self.effective_checks()
+            .into_iter()
+            .find(|spec| !spec.check.tokenless())
+            .map(|spec| {
+                format!(
+                    "check {} needs an acl token, --no-token only allows leader, peers:<n> and dns-recurse",
+                    spec.check
+                )
+            })
+    }
+
+    /// The first option that writes files or runs programs after the wait started, which [Config::hardened] forbids
+    fn needs_privileges(&self) -> Option<&'static str> {
+        [
+            (self.on_attempt.is_some(), "--on-attempt"),
+            (self.on_ready.is_some(), "--on-ready"),
+            (self.on_fail.is_some(), "--on-fail"),
+            (self.on_change.is_some(), "--on-change"),
+            (self.support_bundle.is_some(), "--support-bundle"),
+            (self.state_file.is_some(), "--state-file"),
+            (self.token_prompt.is_some(), "--prompt-token"),
+        ]
+        .into_iter()
+        .find_map(|(set, option)| set.then_some(option))
+    }
+
+    /// The checks a wait evaluates, after applying the profile and defaults
+    pub fn effective_checks(&self) -> Vec<CheckSpec> {
+        let mut checks: Vec<CheckSpec> = match (&self.profile, self.checks.is_empty()) {
+            (Some(profile), _) => profile.checks(),
+            (None, true) if self.no_token => vec![Check::Leader],
+            (None, true) => vec![Check::Raft],
+            (None, false) => Vec::new(),
+        }
+        .into_iter()
+        .map(CheckSpec::from)
+        .collect();
+        for spec in self.checks.iter() {
+            match checks.iter_mut().find(|c| c.check == spec.check) {
+                Some(existing) => *existing = spec.clone(),
+                None => checks.push(spec.clone()),
+            }
+        }
+        // a check per service, so that each reports its own status
+        let mut checks: Vec<CheckSpec> = checks
+            .into_iter()
+            .flat_map(|spec| match &spec.check {
+                Check::Services(names) => names
+                    .iter()
+                    .map(|name| CheckSpec {
+                        check: Check::Service {
+                            name: name.clone(),
+                            min_instances: 1,
+                        },
+                        ..spec.clone()
+                    })
+                    .collect(),
+                _ => vec![spec],
+            })
+            .collect();
+        if let Some(expected) = &self.expect_acl {
+            checks.insert(0, Check::Acl(expected.clone()).into());
+        }
+        if let Some(window) = self.catalog_stable_for {
+            checks.push(Check::CatalogStable(window).into());
+        }
+        if let Some(min) = self.min_peers {
+            match checks
+                .iter_mut()
+                .find(|c| matches!(c.check, Check::Peers(_)))
+            {
+                Some(existing) => existing.check = Check::Peers(min),
+                None => checks.push(Check::Peers(min).into()),
+            }
+        }
+        let services = self
+            .services
+            .iter()
+            .chain(self.min_instances.0.iter().map(|(name, _)| name));
+        for name in services {
+            let known = checks
+                .iter()
+                .any(|c| matches!(&c.check, Check::Service { name: n, .. } if n == name));
+            if !known {
+                checks.push(
+                    Check::Service {
+                        name: name.clone(),
+                        min_instances: 1,
+                    }
+                    .into(),
+                );
+            }
+        }
+        for spec in checks.iter_mut() {
+            if let Check::Service {
+                name,
+                min_instances,
+            } = &mut spec.check
+            {
+                *min_instances = self.min_instances.of(name).unwrap_or(*min_instances);
+            }
+        }
+        for node in &self.nodes {
+            let check = Check::Node(node.clone());
+            if !checks.iter().any(|c| c.check == check) {
+                checks.push(check.into());
+            }
+        }
+        if let Some(last) = self.phases.last() {
+            for spec in checks.iter_mut() {
+                spec.phase.get_or_insert_with(|| last.name.clone());
+            }
+            for phase in &self.phases {
+                if checks.iter().any(|c| c.phase.as_ref() == Some(&phase.name)) {
+                    continue;
+                }
+                if let Some(check) = phase
+                    .default_check()
+                    .filter(|c| !self.no_token || c.tokenless())
+                {
+                    checks.push(CheckSpec {
+                        phase: Some(phase.name.clone()),
+                        ..check.into()
+                    });
+                }
+            }
+            // in the order of the phases, checks of unknown phases last
+            checks.sort_by_key(|c| {
+                self.phases
+                    .iter()
+                    .position(|p| c.phase.as_ref() == Some(&p.name))
+                    .unwrap_or(self.phases.len())
+            });
+        }
+        checks
+    }
+
+    /// One line of "key=value" pairs that says what a wait does: where it connects to and how, which checks it
+    /// evaluates and when it gives up. Logged when a wait starts, secrets are never part of it
+    pub fn banner(&self) -> String {
+        let (url, tls, basic) = match url_base(self) {
+            Ok((url, tls, _)) => {
+                let (url, credentials) = split_userinfo(url);
+                (url, tls, credentials.is_some())
+            }
+            Err(_) => (redact_address(&self.http_addr), self.http_ssl, false),
+        };
+        let (scheme, url) = match self.http_addr.split_once("://") {
+            Some(("unix", _)) => ("unix", redact_address(&self.http_addr)),
+            Some(("srv", _)) if tls => ("srv+https", url),
+            Some(("srv", _)) => ("srv+http", url),
+            _ if tls => ("https", url),
+            _ => ("http", url),
+        };
+        let auth = [
+            (self.http_token.is_some(), "token"),
+            (self.http_token_file.is_some(), "token-file"),
+            (self.http_token_keyring.is_some(), "token-keyring"),
+            (self.token_prompt.is_some(), "token-prompt"),
+            (basic, "basic"),
+            (self.client_cert.is_some(), "mtls"),
+        ]
+        .iter()
+        .filter(|(set, _)| *set)
+        .map(|(_, auth)| *auth)
+        .collect::<Vec<_>>();
+        let verify = match (tls, self.skip_verify) {
+            (false, _) => "none",
+            (true, true) => "skip",
+            (true, false) if self.bootstrap_fingerprint.is_some() => "bootstrap-fingerprint",
+            (true, false) if self.trust_on_bootstrap => "trust-on-bootstrap",
+            (true, false) if self.ca_cert.is_some() => "ca-cert",
+            (true, false) => "webpki-roots",
+        };
+        let policy = self.retry_policy();
+        let mut banner = format!(
+            "scheme={} scheme_from={} url={} auth={} verify={} checks={} checks_policy={}",
+            scheme,
+            scheme_source(self),
+            url,
+            match auth.is_empty() {
+                true => "none".to_owned(),
+                false => auth.join("+"),
+            },
+            verify,
+            self.effective_checks()
+                .iter()
+                .map(|c| c.to_string())
+                .collect::<Vec<_>>()
+                .join(","),
+            self.success_policy.checks,
+        );
+        if !self.fallback_addrs.is_empty() {
+            banner += &format!(
+                " addresses={} targets_policy={}",
+                self.fallback_addrs.len() + 1,
+                self.success_policy.targets
+            );
+        }
+        if !self.phases.is_empty() {
+            let phases = self.phases.iter().map(|p| p.to_string());
+            banner += &format!(" phases={}", phases.collect::<Vec<_>>().join(","));
+        }
+        banner += &format!(
+            " interval={} timeout={}",
+            humantime::format_duration(policy.interval),
+            policy.timeout.map_or("none".to_owned(), |t| {
+                humantime::format_duration(t).to_string()
+            })
+        );
+        if policy.backoff > 1.0 {
+            banner += &format!(
+                " backoff={}x max_interval={}",
+                policy.backoff,
+                humantime::format_duration(policy.max_interval)
+            );
+        }
+        if let Some(max) = policy.max_attempts {
+            banner += &format!(" max_attempts={}", max);
+        }
+        banner
+    }
+
+    /// The effective configuration as json. Secrets are masked, only their presence is shown.
+    pub fn redacted_json(&self) -> serde_json::Value {
+        let secret = |s: &Option<String>| s.as_ref().map(|_| "<redacted>");
+        let duration = |d: &Option<Duration>| d.map(|d| humantime::format_duration(d).to_string());
+        let policy = self.retry_policy();
+        let backoff = serde_json::json!({
+            "factor": policy.backoff,
+            "max_interval_seconds": policy.max_interval.as_secs_f64(),
+            "jitter": policy.jitter,
+            "max_attempts": policy.max_attempts,
+        });
+        serde_json::json!({
+            "address": redact_address(&self.http_addr),
+            "fallback_addresses": self.fallback_addrs.iter().map(|a| redact_address(a)).collect::<Vec<_>>(),
+            "url": url_base(self).map(|(url, ..)| split_userinfo(url).0).ok(),
+            "tls": url_base(self).map(|(_, ssl, _)| ssl).unwrap_or(self.http_ssl),
+            "scheme_from": scheme_source(self),
+            "auto_scheme": self.auto_scheme,
+            "dns_server": self.dns_server,
+            "internal_resolver": self.internal_resolver,
+            "dns_timeout_seconds": self.dns_timeout.as_secs_f64(),
+            "source_address": self.source_address,
+            "timeout_seconds": policy.timeout.map(|t| t.as_secs_f64()),
+            "interval_seconds": policy.interval.as_secs_f64(),
+            "backoff": backoff,
+            "targets_policy": self.success_policy.targets.to_string(),
+            "checks_policy": self.success_policy.checks.to_string(),
+            "reconnect": self.reconnect,
+            "exclusive": self.exclusive,
+            "state_file": self.state_file,
+            "hardened": self.hardened,
+            "checks": self.effective_checks().iter().map(|c| c.to_string()).collect::<Vec<_>>(),
+            "phases": self.phases.iter().map(|p| p.to_string()).collect::<Vec<_>>(),
+            "profile": self.profile.as_ref().map(|p| p.to_string()),
+            "leader_stable_for": duration(&policy.stability_window),
+            "require_leader": self.require_leader,
+            "max_leader_changes": self.max_leader_changes,
+            "min_instances": self.min_instances.to_string(),
+            "min_peers": self.min_peers,
+            "fail_on_flapping": self.fail_on_flapping,
+            "catalog_stable_for": duration(&self.catalog_stable_for),
+            "expect_headers": self.expect_headers.iter().map(|h| h.to_string()).collect::<Vec<_>>(),
+            "max_latency": duration(&self.max_latency),
+            "max_latency_polls": self.max_latency_polls,
+            "token": secret(&self.http_token),
+            "token_file": self.http_token_file,
+            "token_keyring": self.http_token_keyring,
+            "token_prompt": self.token_prompt.is_some(),
+            "retry_token": self.retry_token,
+            "no_token": self.no_token,
+            "skip_verify": self.skip_verify,
+            "strict_config": self.strict_config,
+            "forbid_insecure": self.forbid_insecure,
+            "conflicts": self.conflicts().iter().map(ConfigConflict::to_json).collect::<Vec<_>>(),
+            "proxy": url_base(self).ok().filter(|_| !self.no_env).and_then(|(url, ..)| system_proxy(&url).ok().flatten()).map(|p| redact_address(&p)),
+            "trust_on_bootstrap": self.trust_on_bootstrap,
+            "bootstrap_fingerprint": self.bootstrap_fingerprint,
+            "ca_cert": self.ca_cert,
+            "client_cert": self.client_cert,
+            "client_key": self.client_key,
+            "alpn_protocols": self.alpn_protocols,
+            "tls_resume": self.tls_resume,
+            "drain_bodies": self.drain_bodies,
+            "log_body_bytes": self.log_body_bytes,
+            "blocking_queries": self.blocking_queries,
+            "no_env": self.no_env,
+            "custom_crypto_provider": self.crypto_provider.is_some(),
+            "custom_verifier": self.verifier.is_some(),
+            "audit_log": self.audit_log,
+            "progress": self.progress,
+            "support_bundle": self.support_bundle,
+            "on_attempt": self.on_attempt,
+            "on_ready": self.on_ready,
+            "on_fail": self.on_fail,
+            "on_change": self.on_change,
+            "notify_url": self.notify_url.as_deref().map(redact_address),
+            "notify_token": secret(&self.notify_token),
+            "notify_retries": self.notify_retries,
+            "request_decorators": self.decorators.len(),
+            "custom_transport": self.transport.is_some(),
+            "config_hash": self.redacted_hash(),
+        })
+    }
 }
 
-struct SkippingVerifier();
+#[cfg(feature = "native-transport")]
+#[derive(Debug)]
+struct SkippingVerifier(Arc<CryptoProvider>);
 
+#[cfg(feature = "native-transport")]
 impl ServerCertVerifier for SkippingVerifier {
     fn verify_server_cert(
         &self,
-        _: &Certificate,
-        _: &[Certificate],
-        _: &rustls::ServerName,
-        _: &mut dyn Iterator<Item = &[u8]>,
+        _: &CertificateDer<'_>,
+        _: &[CertificateDer<'_>],
+        _: &ServerName<'_>,
         _: &[u8],
-        _: std::time::SystemTime,
+        _: UnixTime,
     ) -> std::result::Result<ServerCertVerified, rustls::Error> {
-        log::info!("skipping verification (unsafe!)");
         Ok(ServerCertVerified::assertion())
     }
 
     fn verify_tls12_signature(
         &self,
         _: &[u8],
-        _: &Certificate,
-        _: &rustls::internal::msgs::handshake::DigitallySignedStruct,
-    ) -> std::result::Result<rustls::client::HandshakeSignatureValid, rustls::Error> {
-        log::info!("assume is signature ok (unsafe!)");
+        _: &CertificateDer<'_>,
+        _: &DigitallySignedStruct,
+    ) -> std::result::Result<HandshakeSignatureValid, rustls::Error> {
         Ok(HandshakeSignatureValid::assertion())
     }
 
     fn verify_tls13_signature(
         &self,
         _: &[u8],
-        _: &Certificate,
-        _: &rustls::internal::msgs::handshake::DigitallySignedStruct,
+        _: &CertificateDer<'_>,
+        _: &DigitallySignedStruct,
     ) -> std::result::Result<HandshakeSignatureValid, rustls::Error> {
-        log::info!("assume is signature ok (unsafe!)");
         Ok(HandshakeSignatureValid::assertion())
     }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.0.signature_verification_algorithms.supported_schemes()
+    }
+}
+
+/// Accepts only a server certificate with a known sha256 fingerprint
+#[cfg(feature = "native-transport")]
+#[derive(Debug)]
+struct PinnedVerifier(Vec<u8>, Arc<CryptoProvider>);
+
+#[cfg(feature = "native-transport")]
+impl PinnedVerifier {
+    fn parse(fingerprint: &str, provider: Arc<CryptoProvider>) -> Result<Self> {
+        let hex: String = fingerprint.chars().filter(|c| *c != ':').collect();
+        let digest = (0..hex.len())
+            .step_by(2)
+            .map(|i| {
+                hex.get(i..i + 2)
+                    .and_then(|b| u8::from_str_radix(b, 16).ok())
+            })
+            .collect::<Option<Vec<u8>>>()
+            .filter(|d| d.len() == 32)
+            .ok_or_else(|| Error::InvalidFingerprint(fingerprint.to_owned()))?;
+        Ok(Self(digest, provider))
+    }
+}
+
+/// Sha256 digest of `data` with the hash of a cipher suite of `provider`
+#[cfg(feature = "native-transport")]
+fn sha256(provider: &CryptoProvider, data: &[u8]) -> Option<Vec<u8>> {
+    provider
+        .cipher_suites
+        .iter()
+        .map(|suite| match suite {
+            rustls::SupportedCipherSuite::Tls12(suite) => suite.common.hash_provider,
+            rustls::SupportedCipherSuite::Tls13(suite) => suite.common.hash_provider,
+        })
+        .find(|hash| hash.algorithm() == rustls::crypto::hash::HashAlgorithm::SHA256)
+        .map(|hash| hash.hash(data).as_ref().to_vec())
+}
+
+#[cfg(feature = "native-transport")]
+impl ServerCertVerifier for PinnedVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        _: &[CertificateDer<'_>],
+        _: &ServerName<'_>,
+        _: &[u8],
+        _: UnixTime,
+    ) -> std::result::Result<ServerCertVerified, rustls::Error> {
+        match sha256(&self.1, end_entity).as_deref() == Some(self.0.as_slice()) {
+            true => Ok(ServerCertVerified::assertion()),
+            false => Err(rustls::Error::General(
+                "certificate fingerprint does not match the pinned fingerprint".to_owned(),
+            )),
+        }
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> std::result::Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.1.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> std::result::Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.1.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.1.signature_verification_algorithms.supported_schemes()
+    }
 }
 
-fn load_client_cert(path: &str) -> Result<Certificate> {
-    Ok(Certificate(
+#[cfg(feature = "native-transport")]
+fn load_client_cert(path: &str) -> Result<CertificateDer<'static>> {
+    Ok(CertificateDer::from(
         pem::parse(&fs::read_to_string(PathBuf::from(path)).map_err(Error::ReadClientCert)?)
             .map_err(Error::ParseClientCert)?
             .contents,
     ))
 }
 
-fn load_client_key(path: &str) -> Result<PrivateKey> {
-    Ok(PrivateKey(
-        pem::parse(&fs::read_to_string(PathBuf::from(path)).map_err(Error::ReadClientKey)?)
-            .map_err(Error::ParseClientKey)?
-            .contents,
-    ))
+#[cfg(feature = "native-transport")]
+fn load_client_key(path: &str) -> Result<PrivateKeyDer<'static>> {
+    let pem = pem::parse(&fs::read_to_string(PathBuf::from(path)).map_err(Error::ReadClientKey)?)
+        .map_err(Error::ParseClientKey)?;
+    Ok(match pem.tag.as_str() {
+        "RSA PRIVATE KEY" => PrivatePkcs1KeyDer::from(pem.contents).into(),
+        "EC PRIVATE KEY" => PrivateSec1KeyDer::from(pem.contents).into(),
+        _ => PrivatePkcs8KeyDer::from(pem.contents).into(),
+    })
 }
 
+#[cfg(feature = "native-transport")]
 fn add_client_cert(
     config: &Config,
     builder: ConfigBuilder<ClientConfig, WantsClientCert>,
@@ -140,7 +1209,7 @@ fn add_client_cert(
     match &config.client_cert {
         Some(cert) => match &config.client_key {
             Some(key) => Ok(builder
-                .with_single_cert(
+                .with_client_auth_cert(
                     vec![load_client_cert(cert.as_str())?],
                     load_client_key(key.as_str())?,
                 )
@@ -154,213 +1223,2544 @@ fn add_client_cert(
     }
 }
 
-fn add_client_cert2(
-    config: &Config,
-    builder: ConfigBuilder<ClientConfig, WantsTransparencyPolicyOrClientCert>,
-) -> Result<ClientConfig> {
-    match &config.client_cert {
-        Some(cert) => match &config.client_key {
-            Some(key) => Ok(builder
-                .with_single_cert(
-                    vec![load_client_cert(cert.as_str())?],
-                    load_client_key(key.as_str())?,
-                )
-                .map_err(Error::AddClientCert)?),
-            None => Err(Error::MissingClientKey),
-        },
-        None => match config.client_key {
-            Some(_) => Err(Error::MissingClientCert),
-            None => Ok(builder.with_no_client_auth()),
-        },
+/// How the certificate of the agent is verified. Every tls connection of an http agent uses the same policy:
+/// the one to the consul agent, those to the targets of redirects and those tunneled through a proxy
+#[cfg(feature = "native-transport")]
+enum Verification<'a> {
+    /// Against the default root certificates, `ca_cert` and the connect ca roots once they were fetched
+    Roots(Option<&'a [CertificateDer<'static>]>),
+    Custom(Arc<dyn ServerCertVerifier>),
+    /// Only while fetching the connect ca roots with --trust-on-bootstrap
+    Pinned(&'a str),
+    /// Not at all, with --skip-verify or while fetching the connect ca roots without a fingerprint
+    Skip,
+}
+
+#[cfg(feature = "native-transport")]
+impl<'a> Verification<'a> {
+    /// `trusted_roots` are the connect ca roots, once they were fetched with --trust-on-bootstrap
+    fn of(config: &'a Config, trusted_roots: Option<&'a [CertificateDer<'static>]>) -> Self {
+        if config.skip_verify {
+            warn_skip_verify();
+            Verification::Skip
+        } else if let Some(verifier) = &config.verifier {
+            Verification::Custom(verifier.clone())
+        } else if config.trust_on_bootstrap && trusted_roots.is_none() {
+            match &config.bootstrap_fingerprint {
+                Some(fingerprint) => {
+                    log::info!("pin agent certificate to fingerprint: {}", fingerprint);
+                    Verification::Pinned(fingerprint)
+                }
+                None => {
+                    log::warn!(
+                        "fetch the connect ca roots without verifying the agent certificate"
+                    );
+                    Verification::Skip
+                }
+            }
+        } else {
+            Verification::Roots(trusted_roots)
+        }
     }
 }
 
+/// Warn about disabled certificate verification once per process, instead of on every connection
+#[cfg(feature = "native-transport")]
+fn warn_skip_verify() {
+    static WARNED: std::sync::Once = std::sync::Once::new();
+    WARNED.call_once(|| {
+        log::warn!(
+            "TLS CERTIFICATE VERIFICATION IS DISABLED (--skip-verify or CONSUL_HTTP_SSL_VERIFY=false): \
+             the agent, redirect targets and connections through proxies are not authenticated"
+        )
+    });
+}
+
+#[cfg(feature = "native-transport")]
 fn add_verifier(
     config: &Config,
     builder: ConfigBuilder<ClientConfig, WantsVerifier>,
+    provider: Arc<CryptoProvider>,
+    verification: Verification,
 ) -> Result<ClientConfig> {
-    if config.skip_verify {
-        log::info!("add custom verifier");
-        add_client_cert(
-            config,
-            builder.with_custom_certificate_verifier(Arc::new(SkippingVerifier())),
-        )
-    } else {
-        let mut root_store = RootCertStore::empty();
-        root_store.add_server_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.0.iter().map(
-            |anchor| {
-                OwnedTrustAnchor::from_subject_spki_name_constraints(
-                    anchor.subject,
-                    anchor.spki,
-                    anchor.name_constraints,
-                )
-            },
-        ));
-        if let Some(ca) = &config.ca_cert {
-            log::info!("read ca cert from: {}", ca);
-            root_store
-                .add(
-                    &fs::read_to_string(PathBuf::from(ca.as_str()))
-                        .map_err(Error::ReadCaCert)
-                        .and_then(|s| pem::parse(&s).map_err(Error::ParseCaCert))
-                        .map(|pem| Certificate(pem.contents))?,
-                )
-                .map_err(Error::AddCaCert)?;
+    let verifier: Arc<dyn ServerCertVerifier> = match verification {
+        Verification::Skip => Arc::new(SkippingVerifier(provider)),
+        Verification::Custom(verifier) => verifier,
+        Verification::Pinned(fingerprint) => {
+            Arc::new(PinnedVerifier::parse(fingerprint, provider)?)
         }
-        add_client_cert2(config, builder.with_root_certificates(root_store))
-    }
+        Verification::Roots(trusted_roots) => {
+            let mut root_store = RootCertStore::empty();
+            #[cfg(feature = "native-transport")]
+            root_store.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+            if let Some(ca) = &config.ca_cert {
+                log::info!("read ca cert from: {}", ca);
+                root_store
+                    .add(
+                        fs::read_to_string(PathBuf::from(ca.as_str()))
+                            .map_err(Error::ReadCaCert)
+                            .and_then(|s| pem::parse(&s).map_err(Error::ParseCaCert))
+                            .map(|pem| CertificateDer::from(pem.contents))?,
+                    )
+                    .map_err(Error::AddCaCert)?;
+            }
+            for root in trusted_roots.unwrap_or_default() {
+                root_store.add(root.clone()).map_err(Error::AddCaCert)?;
+            }
+            return add_client_cert(config, builder.with_root_certificates(root_store));
+        }
+    };
+    add_client_cert(
+        config,
+        builder
+            .dangerous()
+            .with_custom_certificate_verifier(verifier),
+    )
 }
 
-fn build_tls_config(config: &Config) -> Result<rustls::ClientConfig> {
-    add_verifier(
+#[cfg(feature = "native-transport")]
+fn build_tls_config(
+    config: &Config,
+    trusted_roots: Option<&[CertificateDer<'static>]>,
+) -> Result<rustls::ClientConfig> {
+    let provider = match &config.crypto_provider {
+        Some(provider) => provider.clone(),
+        None => default_crypto_provider()?,
+    };
+    let mut tls = add_verifier(
         config,
-        rustls::ClientConfig::builder()
-            .with_safe_default_cipher_suites()
-            .with_safe_default_kx_groups()
-            .with_protocol_versions(rustls::ALL_VERSIONS)
-            .unwrap(),
+        rustls::ClientConfig::builder_with_provider(provider.clone())
+            .with_safe_default_protocol_versions()
+            .map_err(Error::AddClientCert)?,
+        provider,
+        Verification::of(config, trusted_roots),
+    )?;
+    for protocol in config.alpn_protocols.iter().filter(|p| *p != "http/1.1") {
+        log::warn!(
+            "offering alpn protocol {}, but requests are only made with http/1.1",
+            protocol
+        );
+    }
+    // every agent gets its own cache, shared by all its connections. Sessions of the unverified
+    // bootstrap connection are never resumed by the verifying agent
+    tls.resumption = match config.tls_resume {
+        true => Resumption::in_memory_sessions(32),
+        false => Resumption::disabled(),
+    };
+    tls.alpn_protocols = config
+        .alpn_protocols
+        .iter()
+        .map(|p| p.as_bytes().to_vec())
+        .collect();
+    Ok(tls)
+}
+
+/// Address with the credentials of an embedded userinfo (user:pass@) replaced, for logging
+fn redact_address(addr: &str) -> String {
+    let start = addr.find("://").map(|i| i + 3).unwrap_or(0);
+    let end = addr[start..]
+        .find('/')
+        .map(|i| start + i)
+        .unwrap_or(addr.len());
+    match addr[start..end].rfind('@') {
+        Some(at) => format!("{}<redacted>{}", &addr[..start], &addr[start + at..]),
+        None => addr.to_owned(),
+    }
+}
+
+/// Remove the userinfo from a url, returns the url and a basic authorization header value for it
+fn split_userinfo(base: String) -> (String, Option<String>) {
+    let mut url = match url::Url::parse(&base) {
+        Ok(url) if !url.username().is_empty() || url.password().is_some() => url,
+        _ => return (base, None),
+    };
+    let decode = |s: &str| {
+        percent_encoding::percent_decode_str(s)
+            .decode_utf8_lossy()
+            .into_owned()
+    };
+    let credentials = format!(
+        "{}:{}",
+        decode(url.username()),
+        decode(url.password().unwrap_or_default())
+    );
+    let _ = url.set_username("");
+    let _ = url.set_password(None);
+    (
+        url.to_string(),
+        Some(format!("Basic {}", base64::encode(credentials))),
     )
 }
 
-fn url_base(config: &Config) -> Result<(String, bool)> {
-    if config.http_addr.starts_with("http://") {
+/// Flags and environment variables that contradict each other, or have no effect in combination
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConfigConflict {
+    /// The address has the http scheme, but tls was enabled with --tls or CONSUL_HTTP_SSL. Https is used
+    SchemeUpgraded(String),
+    /// --tls was given, but CONSUL_HTTP_SSL=false. Tls is used
+    TlsFlagOverridesEnv,
+    /// A tls option (named like its flag) is set, but the connection to the agent does not use tls
+    TlsOptionWithoutTls(&'static str),
+}
+
+impl ConfigConflict {
+    /// Stable identifier for machines
+    pub fn code(&self) -> &'static str {
+        match self {
+            ConfigConflict::SchemeUpgraded(_) => "scheme-upgraded",
+            ConfigConflict::TlsFlagOverridesEnv => "tls-flag-overrides-env",
+            ConfigConflict::TlsOptionWithoutTls(_) => "tls-option-without-tls",
+        }
+    }
+
+    pub fn to_json(&self) -> serde_json::Value {
+        serde_json::json!({
+            "code": self.code(),
+            "option": match self {
+                ConfigConflict::TlsOptionWithoutTls(option) => Some(option),
+                _ => None,
+            },
+            "message": self.to_string(),
+        })
+    }
+}
+
+impl Display for ConfigConflict {
+    #[rustfmt::skip]
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConfigConflict::SchemeUpgraded(addr) => write!(f, "address ({}) indicates http transport, but CONSUL_HTTP_SSL=true or --tls, using ssl transport", addr),
+            ConfigConflict::TlsFlagOverridesEnv => write!(f, "--tls overrides CONSUL_HTTP_SSL=false, using ssl transport"),
+            ConfigConflict::TlsOptionWithoutTls(option) => write!(f, "--{} has no effect, the agent is not connected with tls", option),
+        }
+    }
+}
+
+/// Default http and https ports of the agent
+const HTTP_PORT: u16 = 8500;
+const HTTPS_PORT: u16 = 8501;
+
+/// Whether to use tls for an address without a scheme, by its port, with [Config::infer_scheme].
+/// None if the port is not one of the default ports of the agent, or the scheme is given some other way
+fn inferred_tls(config: &Config) -> Option<bool> {
+    let addr = &config.http_addr;
+    if !config.infer_scheme || config.http_ssl || addr.contains(":/") {
+        return None;
+    }
+    let netloc = addr.split('/').next().unwrap_or_default();
+    let port = netloc
+        .rsplit_once(':')
+        .filter(|(_, port)| !port.contains(']'))?
+        .1;
+    match port.parse() {
+        Ok(HTTPS_PORT) => Some(true),
+        Ok(HTTP_PORT) => Some(false),
+        _ => None,
+    }
+}
+
+/// What decided between http and https, for the resolved configuration
+fn scheme_source(config: &Config) -> &'static str {
+    let addr = &config.http_addr;
+    if (addr.starts_with("http://") && !config.http_ssl) || addr.starts_with("https://") {
+        return "address";
+    }
+    if config.http_ssl {
+        return "tls option";
+    }
+    match inferred_tls(config) {
+        Some(true) => "port 8501",
+        Some(false) => "port 8500",
+        None => "default",
+    }
+}
+
+/// Url of the agent and whether it uses tls, with the conflicts between the address, the tls options and
+/// CONSUL_HTTP_SSL found while deciding
+fn url_base(config: &Config) -> Result<(String, bool, Vec<ConfigConflict>)> {
+    let mut conflicts = Vec::new();
+    let (url, tls) = if config.http_addr.starts_with("http://") {
         if config.http_ssl {
-            log::warn!("address ({}) indicates http transport, but CONSUL_HTTP_SSL=true, using ssl transport", config.http_addr);
-            Ok((
-                format!("https://{}", config.http_addr.split_at(6).1.to_owned()),
+            conflicts.push(ConfigConflict::SchemeUpgraded(redact_address(
+                &config.http_addr,
+            )));
+            (
+                format!("https://{}", config.http_addr.split_at(7).1.to_owned()),
                 true,
-            ))
+            )
         } else {
-            Ok((config.http_addr.clone(), false))
+            (config.http_addr.clone(), false)
         }
     } else if config.http_addr.starts_with("https://") {
-        Ok((config.http_addr.clone(), true))
+        (config.http_addr.clone(), true)
     } else if config.http_addr.starts_with("unix:/") {
-        Err(Error::UnixSocketUnsupported)
-    } else if config.http_ssl {
-        Ok((format!("https://{}", config.http_addr), true))
+        if !cfg!(unix) {
+            return Err(Error::UnixSocketUnsupported);
+        }
+        // requests go to the socket, the host is only sent in the Host header
+        ("http://localhost".to_owned(), false)
+    } else if let Some(name) = config.http_addr.strip_prefix("srv://") {
+        // the SRV name stands in for the host until a target was looked up
+        match config.http_ssl {
+            true => (format!("https://{}", name), true),
+            false => (format!("http://{}", name), false),
+        }
+    } else if config.http_ssl || inferred_tls(config) == Some(true) {
+        (format!("https://{}", config.http_addr), true)
     } else {
-        Ok((format!("http://{}", config.http_addr), false))
+        (format!("http://{}", config.http_addr), false)
+    };
+    if !tls {
+        let options = [
+            ("skip-verify", config.skip_verify),
+            ("ca-cert", config.ca_cert.is_some()),
+            ("client-cert", config.client_cert.is_some()),
+            ("client-key", config.client_key.is_some()),
+            ("trust-on-bootstrap", config.trust_on_bootstrap),
+        ];
+        conflicts.extend(
+            options
+                .into_iter()
+                .filter(|(_, set)| *set)
+                .map(|(option, _)| ConfigConflict::TlsOptionWithoutTls(option)),
+        );
     }
+    Ok((url, tls, conflicts))
 }
 
-fn agent_and_url(config: &Config) -> Result<(ureq::Agent, String)> {
-    url_base(config).and_then(|(url, ssl)| {
-        if ssl {
-            Ok(ureq::builder()
-                .https_only(true)
-                .tls_config(Arc::new(build_tls_config(config)?))
-                .build())
-        } else {
-            Ok(ureq::builder().build())
+/// Http agent for the agent address, `tls` if the url is https, verified as [Verification::of] says.
+/// Resolves the agent host to the preferred address, if there is one
+fn build_agent(
+    config: &Config,
+    preferred: &PreferredAddress,
+    tls: bool,
+    proxy: Option<&ureq::Proxy>,
+    trusted_roots: Option<&[CertificateDer<'static>]>,
+) -> Result<Agent> {
+    let preferred = preferred.clone();
+    let mut builder = ureq::builder().resolver(move |netloc: &str| preferred.resolve(netloc));
+    if let Some(proxy) = proxy {
+        builder = builder.proxy(proxy.clone());
+    }
+    if !config.drain_bodies {
+        // a connection with unread data can't be reused
+        builder = builder.max_idle_connections(0);
+    }
+    if tls {
+        // never follow a redirect from https to http
+        builder = builder.https_only(true);
+        #[cfg(feature = "native-transport")]
+        {
+            builder = builder.tls_config(Arc::new(build_tls_config(config, trusted_roots)?));
         }
-        .map(|agent| (agent, format!("{}/v1/operator/raft/configuration", url)))
-    })
+        #[cfg(not(feature = "native-transport"))]
+        let _ = (config, trusted_roots);
+    }
+    Ok(builder.build())
+}
+
+/// The host of `url` is localhost or a loopback address
+fn is_loopback(url: &str) -> bool {
+    match url::Url::parse(url).ok().as_ref().and_then(url::Url::host) {
+        Some(url::Host::Domain(domain)) => domain.eq_ignore_ascii_case("localhost"),
+        Some(url::Host::Ipv4(ip)) => ip.is_loopback(),
+        Some(url::Host::Ipv6(ip)) => ip.is_loopback(),
+        None => false,
+    }
+}
+
+/// Proxy for requests to `url` from the environment, like the consul cli: HTTPS_PROXY or HTTP_PROXY depending on
+/// the scheme, unless the host is a loopback address or matches NO_PROXY. None if requests go out directly
+fn system_proxy(url: &str) -> Result<Option<String>> {
+    let url = match url::Url::parse(url) {
+        Ok(url) => url,
+        Err(_) => return Ok(None),
+    };
+    let env = |name: &str| {
+        std::env::var(name.to_uppercase())
+            .or_else(|_| std::env::var(name))
+            .ok()
+            .filter(|v| !v.is_empty())
+    };
+    let proxy = match env(&format!("{}_proxy", url.scheme())) {
+        Some(proxy) => proxy,
+        None => return Ok(None),
+    };
+    let host = match url.host() {
+        Some(url::Host::Domain("localhost")) | None => return Ok(None),
+        Some(url::Host::Domain(domain)) => domain.to_lowercase(),
+        Some(url::Host::Ipv4(ip)) if ip.is_loopback() => return Ok(None),
+        Some(url::Host::Ipv6(ip)) if ip.is_loopback() => return Ok(None),
+        Some(host) => host.to_string(),
+    };
+    let port = url.port_or_known_default().unwrap_or_default();
+    if let Some(entry) = env("no_proxy")
+        .unwrap_or_default()
+        .split(',')
+        .map(str::trim)
+        .find(|entry| no_proxy_matches(entry, &host, port))
+    {
+        log::debug!("no proxy for {}, it matches {}", host, entry);
+        return Ok(None);
+    }
+    match proxy.contains("://") {
+        true => Ok(Some(proxy)),
+        false => Ok(Some(format!("http://{}", proxy))),
+    }
+}
+
+/// Whether a NO_PROXY entry matches a host: "*", an ip address, a cidr block, or a domain that matches itself and
+/// its subdomains, with or without a leading dot. Entries may have a port
+fn no_proxy_matches(entry: &str, host: &str, port: u16) -> bool {
+    if entry == "*" {
+        return true;
+    }
+    let ip = host
+        .trim_start_matches('[')
+        .trim_end_matches(']')
+        .parse::<std::net::IpAddr>()
+        .ok();
+    if let Some((network, bits)) = entry.split_once('/') {
+        let bits = bits.parse::<u32>().ok();
+        return match (ip, network.parse::<std::net::IpAddr>(), bits) {
+            (
+                Some(std::net::IpAddr::V4(ip)),
+                Ok(std::net::IpAddr::V4(net)),
+                Some(bits @ 0..=32),
+            ) => {
+                let mask = u32::MAX.checked_shl(32 - bits).unwrap_or(0);
+                u32::from(ip) & mask == u32::from(net) & mask
+            }
+            (
+                Some(std::net::IpAddr::V6(ip)),
+                Ok(std::net::IpAddr::V6(net)),
+                Some(bits @ 0..=128),
+            ) => {
+                let mask = u128::MAX.checked_shl(128 - bits).unwrap_or(0);
+                u128::from(ip) & mask == u128::from(net) & mask
+            }
+            _ => false,
+        };
+    }
+    let (name, entry_port) = match entry.rsplit_once(':') {
+        Some((name, p)) if !name.ends_with(':') && p.parse::<u16>().is_ok() => {
+            (name, p.parse::<u16>().ok())
+        }
+        _ => (entry, None),
+    };
+    if entry_port.is_some_and(|p| p != port) {
+        return false;
+    }
+    let name = name
+        .trim_start_matches('[')
+        .trim_end_matches(']')
+        .to_lowercase();
+    match (ip, name.parse::<std::net::IpAddr>()) {
+        (Some(ip), Ok(name)) => ip == name,
+        (None, Err(_)) => {
+            let name = name.trim_start_matches('.');
+            !name.is_empty() && (host == name || host.ends_with(&format!(".{}", name)))
+        }
+        _ => false,
+    }
+}
+
+/// Read a token from the platform credential store, `entry` has the form <service>/<account>
+#[cfg(feature = "keyring")]
+fn read_keyring(entry: &str) -> Result<String> {
+    let (service, account) = entry
+        .split_once('/')
+        .ok_or_else(|| Error::InvalidKeyringEntry(entry.to_owned()))?;
+    log::info!("read token from keyring: {}", entry);
+    keyring::Entry::new(service, account)
+        .and_then(|e| e.get_password())
+        .map(|token| token.trim().to_owned())
+        .map_err(|e| Error::ReadKeyring(e.to_string()))
+}
+
+#[cfg(not(feature = "keyring"))]
+fn read_keyring(_: &str) -> Result<String> {
+    Err(Error::KeyringUnsupported)
+}
+
+/// Customizes requests before they are sent to the agent, e.g. to add signature or tracing headers
+pub trait RequestDecorator: Send + Sync {
+    fn decorate(&self, request: Request) -> Request;
+}
+
+/// A GET request to the agent, for a [Transport]
+#[derive(Debug, Clone)]
+pub struct TransportRequest {
+    pub url: String,
+    /// Including the token and the headers of the [RequestDecorator]s
+    pub headers: Vec<(String, String)>,
+    /// The response must be read completely within this time
+    pub timeout: Duration,
 }
 
-struct HeaderAdder(Option<(&'static str, String)>);
+/// Response of the agent to a [TransportRequest], regardless of its status code
+#[derive(Debug, Clone)]
+pub struct TransportResponse {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+/// Sends requests to the agent, see [Config::transport]. Errors of kind ConnectionRefused or TimedOut are reported
+/// like those of the built-in client
+pub trait Transport: Send + Sync {
+    fn send(&self, request: &TransportRequest) -> std::io::Result<TransportResponse>;
+}
+
+impl<F> RequestDecorator for F
+where
+    F: Fn(Request) -> Request + Send + Sync,
+{
+    fn decorate(&self, request: Request) -> Request {
+        self(request)
+    }
+}
+
+impl RequestDecorator for Vec<Arc<dyn RequestDecorator>> {
+    fn decorate(&self, request: Request) -> Request {
+        self.iter().fold(request, |r, d| d.decorate(r))
+    }
+}
+
+struct HeaderAdder(Vec<(&'static str, String)>);
 
 impl HeaderAdder {
     fn try_new(config: &Config) -> Result<Self> {
         match &config.http_token {
-            Some(token) => Ok(Self(Some(("X-Consul-Token", token.to_owned())))),
+            Some(token) => Ok(Self(vec![("X-Consul-Token", token.to_owned())])),
+            None if config.http_token_keyring.is_some() => Ok(Self(vec![(
+                "X-Consul-Token",
+                read_keyring(config.http_token_keyring.as_deref().unwrap_or_default())?,
+            )])),
             None => match &config.http_token_file {
-                Some(f) => Ok(Self(Some((
+                Some(f) => Ok(Self(vec![(
                     "X-Consul-Token",
                     fs::read_to_string(PathBuf::from(f))
                         .map_err(Error::ReadTokenFile)?
                         .trim()
                         .to_owned(),
-                )))),
-                None => Ok(Self(None)),
+                )])),
+                None => Ok(Self(Vec::new())),
             },
         }
     }
+}
 
-    fn with_header(&self, r: Request) -> Request {
-        match &self.0 {
-            Some((h, v)) => r.set(h, v.as_str()),
-            None => r,
-        }
+impl RequestDecorator for HeaderAdder {
+    fn decorate(&self, r: Request) -> Request {
+        self.0.iter().fold(r, |r, (h, v)| r.set(h, v.as_str()))
     }
 }
 
-fn do_request(
-    agent: &Agent,
-    url: &str,
-    timeout: Duration,
-    header_adder: &HeaderAdder,
-) -> Result<u16> {
-    header_adder
-        .with_header(agent.get(url))
-        .timeout(timeout)
-        .call()
-        .map_err(Error::Request)
-        .map(|r| r.status())
+/// A response from the agent, regardless of its status code
+struct Reply {
+    status: u16,
+    /// Value of the X-Consul-Index header
+    index: Option<u64>,
+    headers: Vec<(String, String)>,
+    body: String,
+    /// Address of the agent that sent the response, None over a unix socket
+    peer: Option<std::net::SocketAddr>,
+}
+
+impl Reply {
+    fn response(&self) -> interpret::Response<'_> {
+        interpret::Response {
+            status: self.status,
+            index: self.index,
+            headers: &self.headers,
+            body: &self.body,
+        }
+    }
+}
+
+/// Longest body that is read, same as the limit of [ureq::Response::into_string]
+const MAX_BODY: u64 = 10 * 1024 * 1024;
+
+/// Longest body of an error response that is read without [Config::drain_bodies], enough for the message
+const MAX_ERROR_BODY: u64 = 4096;
+
+fn do_request(
+    agent: &Agent,
+    route: &Route,
+    url: &str,
+    timeout: Duration,
+    decorator: &dyn RequestDecorator,
+    drain: bool,
+) -> Result<Reply> {
+    let request = match drain {
+        true => agent.get(url),
+        false => agent.get(url).set("Connection", "close"),
+    };
+    let request = decorator.decorate(request).timeout(timeout);
+    if let Route::Custom(transport) = route {
+        return send_custom(transport.as_ref(), &request, timeout);
+    }
+    let response = match route {
+        Route::Direct => match request.call() {
+            Ok(r) | Err(ureq::Error::Status(_, r)) => {
+                let peer = r.remote_addr();
+                Ok((r, Some(peer)))
+            }
+            Err(e) => Err(request_error(e)),
+        },
+        Route::Unix(path) => unix::call(path, &request, timeout, MAX_BODY).map(|r| (r, None)),
+        #[cfg(feature = "native-transport")]
+        Route::Bound(connector) => connector
+            .call(&request, timeout, MAX_BODY)
+            .map(|(r, peer)| (r, Some(peer))),
+        Route::Custom(_) => unreachable!("sent by send_custom"),
+    };
+    match response {
+        Ok((r, peer)) => {
+            let status = r.status();
+            let index = r.header("X-Consul-Index").and_then(|i| i.parse().ok());
+            let headers = r
+                .headers_names()
+                .into_iter()
+                .filter_map(|name| {
+                    let value = r.header(&name)?.to_owned();
+                    Some((name, value))
+                })
+                .collect();
+            // errors only need their message, except autopilot, which reports unhealthy servers with a 429
+            let limit = match drain || status < 400 || status == 429 {
+                true => MAX_BODY,
+                false => MAX_ERROR_BODY,
+            };
+            let mut reader = r.into_reader();
+            let mut body = Vec::new();
+            std::io::Read::read_to_end(&mut std::io::Read::take(&mut reader, limit), &mut body)
+                .map_err(Error::ReadResponse)?;
+            if drain {
+                // read the rest, so the connection goes back to the pool
+                std::io::copy(&mut reader, &mut std::io::sink()).map_err(Error::ReadResponse)?;
+            }
+            Ok(Reply {
+                status,
+                index,
+                headers,
+                peer,
+                body: String::from_utf8_lossy(&body).into_owned(),
+            })
+        }
+        Err(e) => Err(e),
+    }
+}
+
+/// Send a request built by the http agent with a [Transport]
+fn send_custom(transport: &dyn Transport, request: &Request, timeout: Duration) -> Result<Reply> {
+    let url = request.url().to_owned();
+    let headers = request
+        .header_names()
+        .into_iter()
+        .flat_map(|name| {
+            request
+                .all(&name)
+                .into_iter()
+                .map(|value| (name.clone(), value.to_owned()))
+                .collect::<Vec<_>>()
+        })
+        .collect();
+    let response = transport
+        .send(&TransportRequest {
+            url: url.clone(),
+            headers,
+            timeout,
+        })
+        .map_err(|e| Error::Connection(url, e))?;
+    let mut body = response.body;
+    body.truncate(MAX_BODY as usize);
+    Ok(Reply {
+        status: response.status,
+        index: response
+            .headers
+            .iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case("X-Consul-Index"))
+            .and_then(|(_, index)| index.parse().ok()),
+        headers: response.headers,
+        peer: None,
+        body: String::from_utf8_lossy(&body).into_owned(),
+    })
+}
+
+fn lookup_timeout(e: &std::io::Error) -> Option<&probe::LookupTimeout> {
+    e.get_ref()?.downcast_ref::<probe::LookupTimeout>()
+}
+
+/// Error for a failed lookup of the agent address outside of ureq
+#[cfg(feature = "native-transport")]
+pub(crate) fn resolve_error(netloc: &str, e: std::io::Error) -> Error {
+    match lookup_timeout(&e) {
+        Some(timeout) => Error::DnsTimeout(timeout.netloc.clone(), timeout.after),
+        None => Error::DnsQuery(std::io::Error::new(
+            e.kind(),
+            format!("failed to resolve {}: {}", netloc, e),
+        )),
+    }
+}
+
+/// Well known consul ports that don't serve the http api, and what they serve instead
+const CONSUL_PORTS: [(u16, &str); 6] = [
+    (8300, "server rpc"),
+    (8301, "serf lan"),
+    (8302, "serf wan"),
+    (8502, "grpc"),
+    (8503, "grpc tls"),
+    (8600, "dns"),
+];
+
+fn consul_port(port: u16) -> Option<&'static str> {
+    CONSUL_PORTS
+        .iter()
+        .find(|(known, _)| *known == port)
+        .map(|(_, serves)| *serves)
+}
+
+/// [Error::DnsTimeout] if resolving the agent address took too long.
+/// [Error::WrongScheme] if the tls handshake got a plain http response.
+/// [Error::NotHttp] if the response could not be parsed as http, or the connection was closed without a response
+/// by one of the [CONSUL_PORTS], which drop connections that start with bytes they don't understand
+fn request_error(err: ureq::Error) -> Error {
+    let ureq::Error::Transport(transport) = &err else {
+        return Error::Request(err);
+    };
+    let mut source = std::error::Error::source(transport);
+    while let Some(e) = source {
+        if let Some(timeout) = e.downcast_ref::<std::io::Error>().and_then(lookup_timeout) {
+            return Error::DnsTimeout(timeout.netloc.clone(), timeout.after);
+        }
+        source = e.source();
+    }
+    let Some((host, port)) = transport
+        .url()
+        .and_then(|u| Some((u.host_str()?.to_owned(), u.port_or_known_default()?)))
+    else {
+        return Error::Request(err);
+    };
+    let mut source = std::error::Error::source(transport);
+    while let Some(e) = source {
+        let tls = e.downcast_ref::<rustls::Error>().or_else(|| {
+            e.downcast_ref::<std::io::Error>()
+                .and_then(std::io::Error::get_ref)
+                .and_then(|e| e.downcast_ref::<rustls::Error>())
+        });
+        // the first bytes of an http response are no tls record
+        if let Some(rustls::Error::InvalidMessage(rustls::InvalidMessage::InvalidContentType)) = tls
+        {
+            return Error::WrongScheme(format!("{}:{}", host, port), false);
+        }
+        source = e.source();
+    }
+    let garbled = matches!(
+        transport.kind(),
+        ureq::ErrorKind::BadStatus | ureq::ErrorKind::BadHeader
+    );
+    let closed = consul_port(port).is_some() && {
+        let mut source = std::error::Error::source(transport);
+        let mut closed = false;
+        while let Some(e) = source {
+            if let Some(io) = e.downcast_ref::<std::io::Error>() {
+                closed |= matches!(
+                    io.kind(),
+                    std::io::ErrorKind::ConnectionAborted
+                        | std::io::ErrorKind::ConnectionReset
+                        | std::io::ErrorKind::UnexpectedEof
+                );
+            }
+            source = e.source();
+        }
+        closed
+    };
+    match garbled || closed {
+        true => Error::NotHttp(format!("{}:{}", host, port), port),
+        false => Error::Request(err),
+    }
+}
+
+/// The first `bytes` of a body for the log, cut at a character boundary, with control characters escaped
+fn body_prefix(body: &str, bytes: usize) -> String {
+    let end = (0..=bytes.min(body.len()))
+        .rev()
+        .find(|i| body.is_char_boundary(*i))
+        .unwrap_or_default();
+    body[..end]
+        .chars()
+        .flat_map(|c| match c.is_control() {
+            true => c.escape_default().collect::<Vec<_>>(),
+            false => vec![c],
+        })
+        .collect()
+}
+
+fn excerpt(body: &str) -> String {
+    let body = body.trim();
+    match body.char_indices().nth(120) {
+        Some((end, _)) => format!("{}...", &body[..end]),
+        None => body.to_owned(),
+    }
+}
+
+/// Sends requests to the consul agent
+struct Client {
+    agent: Agent,
+    base: String,
+    /// The token and authorization headers first, then [Config::decorators]
+    decorators: Vec<Arc<dyn RequestDecorator>>,
+    /// Set with --trust-on-bootstrap, until the connect ca roots were fetched with `agent`
+    bootstrap: Option<Bootstrap>,
+    /// Address of the agent to send requests to, if its host name resolves to several
+    preferred: PreferredAddress,
+    /// From the environment, see [system_proxy]
+    proxy: Option<ureq::Proxy>,
+    /// Read response bodies to the end, see [Config::drain_bodies]
+    drain: bool,
+    /// Only request endpoints that need no acl token, see [Config::no_token]
+    tokenless: bool,
+    /// With a srv:// address, the host of `base` is the target looked up last
+    srv: Option<SrvLookup>,
+    route: Route,
+    /// Index and wait time of the next request, if it should be a blocking query
+    blocking: std::cell::Cell<Option<(u64, Duration)>>,
+}
+
+/// How the requests built by the http agent get to the consul agent
+enum Route {
+    /// The http agent connects by itself
+    Direct,
+    /// With a unix:// address, requests go to this socket instead of the host of the url
+    Unix(PathBuf),
+    /// Connections are made from [Config::source_address]
+    #[cfg(feature = "native-transport")]
+    Bound(transport::BoundConnector),
+    /// Requests are sent by [Config::transport]
+    Custom(Arc<dyn Transport>),
+}
+
+/// Route for the agent address, `tls` if the url is https, verified as [Verification::of] says
+fn build_route(
+    config: &Config,
+    preferred: &PreferredAddress,
+    tls: bool,
+    trusted_roots: Option<&[CertificateDer<'static>]>,
+) -> Result<Route> {
+    if let Some(transport) = &config.transport {
+        return Ok(Route::Custom(transport.clone()));
+    }
+    if let Some(path) = unix::socket_path(&config.http_addr) {
+        return Ok(Route::Unix(PathBuf::from(path)));
+    }
+    #[cfg(feature = "native-transport")]
+    if let Some(source) = &config.source_address {
+        let tls = match tls {
+            true => Some(Arc::new(build_tls_config(config, trusted_roots)?)),
+            false => None,
+        };
+        return Ok(Route::Bound(transport::BoundConnector::new(
+            transport::Source::parse(source),
+            preferred.clone(),
+            tls,
+        )));
+    }
+    #[cfg(not(feature = "native-transport"))]
+    let _ = (preferred, tls, trusted_roots);
+    Ok(Route::Direct)
+}
+
+/// Agent address that is an SRV name, looked up every round
+#[cfg_attr(not(feature = "native-transport"), allow(dead_code))]
+struct SrvLookup {
+    name: String,
+    /// From --dns-server or /etc/resolv.conf
+    #[cfg(feature = "native-transport")]
+    nameserver: Option<String>,
+    /// Agent url with the SRV name as host, the target replaces the host and port
+    url: url::Url,
+    /// Target of the last lookup, "host:port"
+    target: Option<String>,
+    timeout: Duration,
+}
+
+/// State of --trust-on-bootstrap
+struct Bootstrap {
+    config: Config,
+    /// Agent and route that verify against the connect ca roots
+    trusted: std::cell::OnceCell<(Agent, Route)>,
+}
+
+/// Result of evaluating a check against the agent
+struct Evaluation {
+    /// Url of the last request made
+    target: String,
+    /// The last response, if the agent responded
+    reply: Option<Reply>,
+    /// Why the last request failed, if it did
+    error: Option<Error>,
+    outcome: Outcome,
+}
+
+/// The error to fail with, after an evaluation has failed
+fn failure(target: &str, reply: Option<Reply>, error: Option<Error>) -> Error {
+    match (error, reply) {
+        (Some(err), _) => err,
+        (None, Some(reply)) if is_permission_denied(&reply) => {
+            Error::PermissionDenied(missing_permission(&reply.body))
+        }
+        (None, Some(reply)) => Error::Status(reply.status, excerpt(&reply.body)),
+        (None, None) => Error::General(format!("{} failed", target)),
+    }
+}
+
+/// Whether the acl token was rejected for lacking a permission, rather than for not existing
+fn is_permission_denied(reply: &Reply) -> bool {
+    reply.status == 403 && reply.body.contains("Permission denied")
+}
+
+/// The permission named by the body of a 403 response, like `operator:read` or `service:read on "web"`. Consul names
+/// it since 1.12: `Permission denied: token with AccessorID '...' lacks permission 'operator:read'`
+fn missing_permission(body: &str) -> Option<String> {
+    let (_, rest) = body.split_once("lacks permission ")?;
+    let rest = rest.lines().next()?.trim();
+    let (permission, resource) = match rest.strip_prefix('\'') {
+        Some(quoted) => quoted.split_once('\'')?,
+        None => rest.split_once(' ').unwrap_or((rest, "")),
+    };
+    Some(format!("{}{}", permission, resource.trim_end()))
+}
+
+/// Outcome for responses that don't indicate success
+fn classify(response: &Result<Reply>) -> Outcome {
+    match response {
+        Ok(reply) if reply.status < 300 || reply.status == 500 => {
+            Outcome::NotReady(NotReadyReason::from_reply(reply))
+        }
+        Ok(reply) => Outcome::Failed(NotReadyReason::from_reply(reply)),
+        Err(err) => Outcome::Failed(NotReadyReason::from_error(err)),
+    }
+}
+
+impl Client {
+    fn new(config: &Config) -> Result<Self> {
+        #[cfg(not(feature = "native-transport"))]
+        if config.transport.is_none() {
+            return Err(Error::General(
+                "without the native-transport feature, requests need a Config::transport"
+                    .to_owned(),
+            ));
+        }
+        let preferred = match &config.dns_server {
+            #[cfg(feature = "native-transport")]
+            Some(server) => {
+                PreferredAddress::new(Arc::new(dns::Nameserver::new(server, config.dns_timeout)))
+            }
+            #[cfg(feature = "native-transport")]
+            None if config.internal_resolver => {
+                PreferredAddress::new(Arc::new(dns::InternalResolver::new(config.dns_timeout)))
+            }
+            _ => PreferredAddress::new(Arc::new(probe::SystemResolver::new(config.dns_timeout))),
+        };
+        let (base, tls, _) = url_base(config)?;
+        if inferred_tls(config).is_some() {
+            log::info!(
+                "no scheme in address {}, using {} for {}",
+                redact_address(&config.http_addr),
+                if tls { "https" } else { "http" },
+                scheme_source(config)
+            );
+        }
+        let proxy = match config.no_env {
+            true => None,
+            false => system_proxy(&base)?,
+        }
+        .filter(|proxy| match &config.source_address {
+            Some(source) => {
+                log::warn!(
+                    "ignore proxy {}, connections are made from source address {}",
+                    redact_address(proxy),
+                    source
+                );
+                false
+            }
+            None => true,
+        })
+        .map(|proxy| {
+            log::info!("connect through proxy {}", redact_address(&proxy));
+            ureq::Proxy::new(&proxy)
+                .map_err(|e| Error::InvalidProxy(format!("{}: {}", redact_address(&proxy), e)))
+        })
+        .transpose()?;
+        let agent = build_agent(config, &preferred, tls, proxy.as_ref(), None)?;
+        // credentials from the address are sent as a header, to keep them out of logs and reports
+        let (base, authorization) = split_userinfo(base);
+        let mut header_adder = HeaderAdder::try_new(config)?;
+        header_adder
+            .0
+            .extend(authorization.map(|a| ("Authorization", a)));
+        // without tls, --trust-on-bootstrap is reported as a conflict
+        let bootstrap =
+            (config.trust_on_bootstrap && !config.skip_verify && tls).then(|| Bootstrap {
+                config: config.clone(),
+                trusted: Default::default(),
+            });
+        let srv = match config.http_addr.starts_with("srv://") {
+            true => {
+                let url = url::Url::parse(&base)
+                    .map_err(|e| Error::General(format!("invalid srv address: {}", e)))?;
+                Some(SrvLookup {
+                    name: url.host_str().unwrap_or_default().to_owned(),
+                    #[cfg(feature = "native-transport")]
+                    nameserver: config.dns_server.clone().or_else(dns::system_nameserver),
+                    url,
+                    target: None,
+                    timeout: config.dns_timeout,
+                })
+            }
+            false => None,
+        };
+        let route = build_route(config, &preferred, tls, None)?;
+        match &route {
+            Route::Unix(path) => log::info!("connect over unix socket {}", path.display()),
+            #[cfg(feature = "native-transport")]
+            Route::Bound(_) => log::info!(
+                "connect from source address {}",
+                config.source_address.as_deref().unwrap_or_default()
+            ),
+            Route::Custom(_) => log::info!("send requests with the configured transport"),
+            Route::Direct => {}
+        }
+        Ok(Self {
+            agent,
+            // keep a path prefix like https://gateway/consul, but don't end up with //v1/...
+            base: base.trim_end_matches('/').to_owned(),
+            decorators: std::iter::once(Arc::new(header_adder) as Arc<dyn RequestDecorator>)
+                .chain(config.decorators.iter().cloned())
+                .collect(),
+            bootstrap,
+            preferred,
+            proxy,
+            drain: config.drain_bodies,
+            tokenless: config.no_token,
+            srv,
+            route,
+            blocking: Default::default(),
+        })
+    }
+
+    /// Look up the SRV name of the agent address and send the next requests to the first target, in the order of
+    /// priority and weight. `switch` moves on to another target than the last one, if there is one
+    #[cfg(feature = "native-transport")]
+    fn lookup_srv(&mut self, switch: bool) -> Result<()> {
+        let srv = match &mut self.srv {
+            Some(srv) => srv,
+            None => return Ok(()),
+        };
+        let nameserver = srv.nameserver.as_deref().ok_or_else(|| {
+            Error::DnsQuery(std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                "no nameserver to look up srv records with, set --dns-server",
+            ))
+        })?;
+        let targets = dns::srv_order(dns::query_srv(nameserver, &srv.name, srv.timeout).map_err(
+            |e| match matches!(
+                e.kind(),
+                std::io::ErrorKind::TimedOut | std::io::ErrorKind::WouldBlock
+            ) {
+                true => Error::DnsTimeout(srv.name.clone(), srv.timeout),
+                false => Error::DnsQuery(e),
+            },
+        )?);
+        let netloc = |t: &dns::SrvTarget| format!("{}:{}", t.target.trim_end_matches('.'), t.port);
+        let chosen = targets
+            .iter()
+            .find(|t| !switch || srv.target.as_deref() != Some(netloc(t).as_str()))
+            .or(targets.first())
+            .ok_or_else(|| {
+                Error::DnsQuery(std::io::Error::new(
+                    std::io::ErrorKind::NotFound,
+                    format!("no srv records for {}", srv.name),
+                ))
+            })?;
+        let target = netloc(chosen);
+        self.preferred.hint(
+            &target,
+            chosen
+                .addresses
+                .iter()
+                .map(|ip| std::net::SocketAddr::new((*ip).into(), chosen.port))
+                .collect(),
+        );
+        if srv.target.as_deref() != Some(target.as_str()) {
+            log::info!(
+                "{} has {} target(s), use {} (priority {}, weight {})",
+                srv.name,
+                targets.len(),
+                target,
+                chosen.priority,
+                chosen.weight
+            );
+        }
+        let mut url = srv.url.clone();
+        url.set_host(Some(chosen.target.trim_end_matches('.')))
+            .and_then(|_| {
+                url.set_port(Some(chosen.port))
+                    .map_err(|_| url::ParseError::InvalidPort)
+            })
+            .map_err(|e| Error::General(format!("invalid srv target {}: {}", target, e)))?;
+        self.base = url.as_str().trim_end_matches('/').to_owned();
+        srv.target = Some(target);
+        Ok(())
+    }
+
+    /// Without the native transport there are no dns queries to look up the SRV name with
+    #[cfg(not(feature = "native-transport"))]
+    fn lookup_srv(&mut self, _: bool) -> Result<()> {
+        match &self.srv {
+            Some(srv) => Err(Error::General(format!(
+                "looking up {} needs the native-transport feature",
+                srv.name
+            ))),
+            None => Ok(()),
+        }
+    }
+
+    /// Probe all addresses of the agent at once and send the next requests to the first one that answers
+    fn probe(&self, timeout: Duration) -> Option<std::net::SocketAddr> {
+        // the proxy connects to the agent, not us, a unix socket has no addresses to choose from, and probing
+        // would not connect from the source address
+        if self.proxy.is_some() || !matches!(self.route, Route::Direct) {
+            return None;
+        }
+        let url = url::Url::parse(&self.base).ok()?;
+        let netloc = format!("{}:{}", url.host()?, url.port_or_known_default()?);
+        self.preferred.probe(&netloc, timeout)
+    }
+
+    /// Agent and route to poll with, fetches the connect ca roots first if trust is still to be bootstrapped
+    fn agent(&self, timeout: Duration) -> Result<(&Agent, &Route)> {
+        let bootstrap = match &self.bootstrap {
+            Some(bootstrap) => bootstrap,
+            None => return Ok((&self.agent, &self.route)),
+        };
+        if let Some((agent, route)) = bootstrap.trusted.get() {
+            return Ok((agent, route));
+        }
+        let url = self.url("/v1/connect/ca/roots");
+        let reply = do_request(
+            &self.agent,
+            &self.route,
+            &url,
+            timeout,
+            &self.decorators,
+            self.drain,
+        )?;
+        if reply.status != 200 {
+            return Err(Error::Status(reply.status, excerpt(&reply.body)));
+        }
+        let roots = serde_json::from_str::<serde_json::Value>(&reply.body)
+            .map_err(|e| Error::BootstrapTrust(e.to_string()))?["Roots"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .filter_map(|root| root["RootCert"].as_str())
+            .map(|pem| {
+                pem::parse(pem)
+                    .map(|pem| CertificateDer::from(pem.contents))
+                    .map_err(|e| Error::BootstrapTrust(e.to_string()))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        if roots.is_empty() {
+            return Err(Error::BootstrapTrust("agent returned no roots".to_owned()));
+        }
+        log::info!("trust {} connect ca root(s) from now on", roots.len());
+        let agent = build_agent(
+            &bootstrap.config,
+            &self.preferred,
+            true,
+            self.proxy.as_ref(),
+            Some(&roots),
+        )?;
+        let route = build_route(&bootstrap.config, &self.preferred, true, Some(&roots))?;
+        let (agent, route) = bootstrap.trusted.get_or_init(|| (agent, route));
+        Ok((agent, route))
+    }
+
+    /// Url of an api path, below the path prefix of the agent address if there is one
+    fn url(&self, path: &str) -> String {
+        format!("{}{}", self.base, path)
+    }
+
+    /// Host name or address of the agent
+    fn host(&self) -> String {
+        url::Url::parse(&self.base)
+            .ok()
+            .and_then(|u| {
+                u.host().map(|h| match h {
+                    url::Host::Ipv6(addr) => format!("[{}]", addr),
+                    host => host.to_string(),
+                })
+            })
+            .unwrap_or_else(|| "localhost".to_owned())
+    }
+
+    /// Make the requests until [Client::unblock] blocking queries: the agent responds once the index of the
+    /// result is past `index` or after `wait`
+    fn block(&self, index: u64, wait: Duration) {
+        self.blocking.set(Some((index, wait)));
+    }
+
+    fn unblock(&self) {
+        self.blocking.set(None);
+    }
+
+    fn get(&self, path: &str, timeout: Duration) -> Result<Reply> {
+        let (agent, route) = self.agent(timeout)?;
+        let url = match self.blocking.get() {
+            Some((index, wait)) => format!(
+                "{}{}index={}&wait={}s",
+                self.url(path),
+                if path.contains('?') { '&' } else { '?' },
+                index,
+                wait.as_secs().max(1)
+            ),
+            None => self.url(path),
+        };
+        let reply = do_request(agent, route, &url, timeout, &self.decorators, self.drain)?;
+        // how the http server of go answers plain http on a tls port
+        if reply.status == 400
+            && reply
+                .body
+                .contains("Client sent an HTTP request to an HTTPS server")
+        {
+            let netloc = url::Url::parse(&url)
+                .ok()
+                .and_then(|u| Some(format!("{}:{}", u.host_str()?, u.port_or_known_default()?)));
+            return Err(Error::WrongScheme(netloc.unwrap_or(url), true));
+        }
+        Ok(reply)
+    }
+
+    /// GET `path` and decide on the outcome of responses with a status the `interpreter` accepts with it
+    fn evaluate(
+        &self,
+        path: &str,
+        timeout: Duration,
+        interpreter: &dyn ResponseInterpreter,
+    ) -> Evaluation {
+        let response = self.get(path, timeout);
+        let outcome = match &response {
+            Ok(reply) if interpreter.statuses().contains(&reply.status) => {
+                interpreter.interpret(&reply.response())
+            }
+            _ => classify(&response),
+        };
+        let (reply, error) = match response {
+            Ok(reply) => (Some(reply), None),
+            Err(err) => (None, Some(err)),
+        };
+        Evaluation {
+            target: self.url(path),
+            reply,
+            error,
+            outcome,
+        }
+    }
+}
+
+impl NotReadyReason {
+    fn from_reply(reply: &Reply) -> Self {
+        match reply.status {
+            500 if reply.body.contains("No cluster leader") => NotReadyReason::NoLeader,
+            403 if reply.body.contains("must be bootstrapped") => {
+                NotReadyReason::AclNotBootstrapped
+            }
+            _ if is_permission_denied(reply) => NotReadyReason::PermissionDenied {
+                permission: missing_permission(&reply.body),
+            },
+            code => NotReadyReason::Status {
+                code,
+                body_excerpt: excerpt(&reply.body),
+            },
+        }
+    }
+
+    fn from_error(err: &Error) -> Self {
+        let transport = match err {
+            Error::Request(ureq::Error::Transport(t)) => t,
+            Error::ReadResponse(e) if is_timeout(e.kind()) => return NotReadyReason::Timeout,
+            Error::NotHttp(addr, _) => return NotReadyReason::NotHttp { addr: addr.clone() },
+            Error::WrongScheme(addr, tls) => {
+                return NotReadyReason::WrongScheme {
+                    addr: addr.clone(),
+                    tls: *tls,
+                }
+            }
+            Error::DnsTimeout(name, after) => {
+                return NotReadyReason::DnsTimeout {
+                    name: name.clone(),
+                    after: *after,
+                }
+            }
+            Error::UnixSocket(path, e) | Error::Connection(path, e) => {
+                return match e.kind() {
+                    std::io::ErrorKind::ConnectionRefused => {
+                        NotReadyReason::ConnectionRefused { addr: path.clone() }
+                    }
+                    kind if is_timeout(kind) => NotReadyReason::Timeout,
+                    _ => NotReadyReason::Transport(err.to_string()),
+                }
+            }
+            rest => return NotReadyReason::Transport(rest.to_string()),
+        };
+        if transport.kind() == ureq::ErrorKind::Dns {
+            return NotReadyReason::Dns(transport.to_string());
+        }
+        let mut source = std::error::Error::source(transport);
+        while let Some(e) = source {
+            if let Some(io) = e.downcast_ref::<std::io::Error>() {
+                match io.kind() {
+                    std::io::ErrorKind::ConnectionRefused => {
+                        return NotReadyReason::ConnectionRefused {
+                            addr: transport
+                                .url()
+                                .and_then(|u| {
+                                    Some(format!(
+                                        "{}:{}",
+                                        u.host_str()?,
+                                        u.port_or_known_default()?
+                                    ))
+                                })
+                                .unwrap_or_default(),
+                        }
+                    }
+                    kind if is_timeout(kind) => return NotReadyReason::Timeout,
+                    _ => {}
+                }
+            }
+            source = e.source();
+        }
+        NotReadyReason::Transport(transport.to_string())
+    }
+}
+
+fn is_timeout(kind: std::io::ErrorKind) -> bool {
+    matches!(
+        kind,
+        std::io::ErrorKind::TimedOut | std::io::ErrorKind::WouldBlock
+    )
+}
+
+/// Collect details about the cluster. Failures are not fatal, the agent is online already.
+fn agent_info(client: &Client, timeout: Duration) -> AgentInfo {
+    let get = |path: &str| {
+        client
+            .get(path, timeout)
+            .ok()
+            .filter(|reply| reply.status == 200)
+            .and_then(|reply| serde_json::from_str::<serde_json::Value>(&reply.body).ok())
+            .or_else(|| {
+                log::debug!("failed to get {}", path);
+                None
+            })
+    };
+    AgentInfo {
+        leader: get(checks::LEADER_PATH)
+            .and_then(|v| v.as_str().map(str::to_owned))
+            .filter(|leader| !leader.is_empty()),
+        version: (!client.tokenless)
+            .then(|| get("/v1/agent/self"))
+            .flatten()
+            .and_then(|v| {
+                v.pointer("/Config/Version")
+                    .and_then(|v| v.as_str())
+                    .map(str::to_owned)
+            }),
+        address: None,
+    }
+}
+
+fn builtin_observers(config: &Config) -> Result<Vec<Box<dyn Observer + Send>>> {
+    let mut observers: Vec<Box<dyn Observer + Send>> = Vec::new();
+    if let Some(path) = &config.audit_log {
+        observers.push(Box::new(audit::AuditLog::open(path, config)?));
+    }
+    if let Some(path) = &config.progress {
+        observers.push(Box::new(progress::Progress::open(path)?));
+    }
+    if let Some(dir) = &config.support_bundle {
+        observers.push(Box::new(bundle::SupportBundle::new(dir, config)));
+    }
+    if let Some(cmd) = &config.on_attempt {
+        observers.push(Box::new(hooks::AttemptHook::new(cmd)));
+    }
+    if config.on_ready.is_some() || config.on_fail.is_some() {
+        observers.push(Box::new(hooks::TerminalHook::new(
+            config.on_ready.as_deref(),
+            config.on_fail.as_deref(),
+        )));
+    }
+    if let Some(cmd) = &config.on_change {
+        observers.push(Box::new(hooks::ChangeHook::new(cmd)));
+    }
+    if let Some(url) = &config.notify_url {
+        observers.push(Box::new(notify::Notifier::new(
+            url,
+            config.notify_token.as_deref(),
+            config.notify_retries,
+        )));
+    }
+    Ok(observers)
 }
 
 pub fn wait(config: Config) -> Result<()> {
-    let (agent, url) = agent_and_url(&config)?;
-    let header_adder = HeaderAdder::try_new(&config)?;
-    let start_time = std::time::SystemTime::now();
-    let interval = Duration::from_secs(config.interval.unwrap_or(10));
+    wait_observed(config, &mut ())
+}
+
+/// Same as [wait], but reports every attempt to the provided observer
+pub fn wait_observed(config: Config, observer: &mut dyn Observer) -> Result<()> {
+    log::info!("starting wait: {}", config.banner());
+    if !config.fallback_addrs.is_empty() {
+        return wait_targets(config, observer);
+    }
+    let mut waiter = match Waiter::new(config) {
+        Ok(waiter) => waiter,
+        Err(e) => return Err(finish_failed(observer, e)),
+    };
     loop {
-        log::debug!("request...");
-        let timeout = std::cmp::max(
-            config
-                .timeout
-                .map(|global_timeout| {
+        match waiter.poll_once_observed(observer) {
+            PollOutcome::Online(_) => break Ok(()),
+            PollOutcome::Failed(e) => break Err(e),
+            PollOutcome::Pending { retry_in } => {
+                log::debug!("sleep {} millis", retry_in.as_millis());
+                std::thread::sleep(retry_in)
+            }
+        }
+    }
+}
+
+/// [wait_observed] with [Config::fallback_addrs]: every round, the addresses are polled in turn until the targets
+/// policy is satisfied. An address whose wait failed is not polled again
+fn wait_targets(config: Config, observer: &mut dyn Observer) -> Result<()> {
+    let policy = config.success_policy.targets;
+    let mut first = match Waiter::new(config.clone()) {
+        Ok(waiter) => waiter,
+        Err(e) => return Err(finish_failed(observer, e)),
+    };
+    // the builtin observers see the attempts of all addresses, and finish once
+    let mut builtin = std::mem::take(&mut first.builtin);
+    let mut observers = (observer, &mut builtin);
+    let mut targets = vec![(first, None)];
+    for addr in &config.fallback_addrs {
+        let target = Config {
+            http_addr: addr.clone(),
+            fallback_addrs: Vec::new(),
+            exclusive: None,
+            state_file: None,
+            ..config.clone()
+        };
+        match Waiter::with_observers(target, Vec::new()) {
+            Ok(waiter) => targets.push((waiter, None)),
+            Err(e) => return Err(finish_failed(&mut observers, e)),
+        }
+    }
+    loop {
+        let mut online = Vec::with_capacity(targets.len());
+        let mut retry_in = None::<Duration>;
+        for (waiter, failed) in targets.iter_mut() {
+            if failed.is_some() {
+                online.push(false);
+                continue;
+            }
+            match waiter.round(&mut observers) {
+                PollOutcome::Online(_) => online.push(true),
+                PollOutcome::Pending { retry_in: r } => {
+                    retry_in = Some(retry_in.map_or(r, |retry_in| retry_in.min(r)));
+                    online.push(false);
+                }
+                PollOutcome::Failed(e) => {
+                    log::info!(
+                        "giving up on {}: {}",
+                        redact_address(&waiter.config.http_addr),
+                        e
+                    );
+                    *failed = Some(waiter.in_phase(e));
+                    online.push(false);
+                }
+            }
+            // the remaining addresses don't matter once one is online
+            if policy == Quantifier::Any && online.last() == Some(&true) {
+                break;
+            }
+        }
+        if policy.holds(online) {
+            observers.finished(&Ok(()));
+            return Ok(());
+        }
+        let failures = targets
+            .iter()
+            .filter(|(_, failed)| failed.is_some())
+            .count();
+        let hopeless = match policy {
+            Quantifier::Any => failures == targets.len(),
+            Quantifier::All => failures > 0,
+        };
+        if hopeless {
+            let e = targets
+                .iter_mut()
+                .find_map(|(_, failed)| failed.take())
+                .unwrap_or_else(|| Error::General("no address to poll".to_owned()));
+            return Err(finish_failed(&mut observers, e));
+        }
+        if let Some(retry_in) = retry_in {
+            log::debug!("sleep {} millis", retry_in.as_millis());
+            std::thread::sleep(retry_in)
+        }
+    }
+}
+
+/// Evaluate the checks forever and report every change between online and offline to [Observer::changed].
+/// A new state is only reported once it lasted for `debounce`, the first state is reported right away.
+/// Polls as the [Config::retry_policy] says, but never gives up, `reconnect` is ignored as well.
+/// Only returns if the watch can't be started.
+pub fn watch(
+    mut config: Config,
+    debounce: Duration,
+    observer: &mut dyn Observer,
+) -> Result<std::convert::Infallible> {
+    config.retry = Some(RetryPolicy {
+        timeout: None,
+        max_attempts: None,
+        ..config.retry_policy()
+    });
+    config.reconnect = true;
+    // there is no deadline to keep
+    config.state_file = None;
+    if !config.fallback_addrs.is_empty() {
+        log::warn!(
+            "only {} is watched, the other addresses are ignored",
+            redact_address(&config.http_addr)
+        );
+    }
+    // all checks are evaluated in every round while watching
+    config.phases.clear();
+    for spec in config.checks.iter_mut() {
+        spec.phase = None;
+    }
+    log::info!("starting watch: {}", config.banner());
+    let mut waiter = match Waiter::new(config) {
+        Ok(waiter) => waiter,
+        Err(e) => return Err(finish_failed(observer, e)),
+    };
+    let mut reported = None;
+    let mut pending: Option<(State, Instant)> = None;
+    loop {
+        let round_start = Instant::now();
+        let mut reason = LastReason::default();
+        let state = match waiter.watch_round(&mut (&mut *observer, &mut reason)) {
+            PollOutcome::Online(_) => State::Online,
+            PollOutcome::Pending { .. } => State::Offline,
+            PollOutcome::Failed(e) => {
+                reason.0 = Some(e.to_string());
+                State::Offline
+            }
+        };
+        if reported == Some(state) {
+            pending = None;
+        } else {
+            let since = match pending {
+                Some((candidate, since)) if candidate == state => since,
+                _ => pending.insert((state, round_start)).1,
+            };
+            if reported.is_none() || since.elapsed() >= debounce {
+                let transition = Transition {
+                    from: reported,
+                    to: state,
+                    reason: reason.0.as_deref().filter(|_| state == State::Offline),
+                };
+                match transition.reason {
+                    Some(reason) => log::info!("consul is {}: {}", state, reason),
+                    None => log::info!("consul is {}", state),
+                }
+                waiter.report_change(observer, &transition);
+                reported = Some(state);
+                pending = None;
+            } else {
+                log::info!(
+                    "consul is {} for {}, wait for {} before reporting it",
+                    state,
+                    compact_duration(since.elapsed()),
+                    compact_duration(debounce)
+                );
+            }
+        }
+        let pause = waiter.policy.jittered(waiter.interval());
+        std::thread::sleep(pause.saturating_sub(round_start.elapsed()));
+    }
+}
+
+/// Remembers why the last attempt that was not online failed
+#[derive(Default)]
+struct LastReason(Option<String>);
+
+impl Observer for LastReason {
+    fn attempt(&mut self, attempt: &Attempt) {
+        if let Some(reason) = attempt.outcome.reason() {
+            self.0 = Some(format!("{}: {}", attempt.check, reason));
+        }
+    }
+}
+
+/// Wait until a tcp connection to `addr` ("host:port") succeeds, retrying every second like wait-for-it.sh.
+/// Gives up after `timeout`, or never if it is None, like `-t 0` of wait-for-it.sh. Returns how long the wait took.
+pub fn wait_tcp(addr: &str, timeout: Option<Duration>) -> Result<Duration> {
+    let start = Instant::now();
+    loop {
+        let remaining = timeout.map(|t| t.saturating_sub(start.elapsed()));
+        let connect_timeout = remaining
+            .unwrap_or(Duration::MAX)
+            .clamp(Duration::from_millis(100), Duration::from_secs(1));
+        let result = connect_any(addr, connect_timeout);
+        match result {
+            Ok(_) => return Ok(start.elapsed()),
+            Err(e) => log::info!("{}: not available yet: {}", addr, e),
+        }
+        if remaining.is_some_and(|r| r.is_zero()) {
+            return Err(Error::Timeout(start.elapsed(), ReasonHistory::default()));
+        }
+        let remaining = timeout.map_or(Duration::MAX, |t| t.saturating_sub(start.elapsed()));
+        std::thread::sleep(Duration::from_secs(1).min(remaining));
+    }
+}
+
+/// Connect to the first reachable address `addr` resolves to
+fn connect_any(addr: &str, timeout: Duration) -> std::io::Result<std::net::TcpStream> {
+    let mut last = std::io::Error::new(
+        std::io::ErrorKind::NotFound,
+        format!("{} did not resolve to any address", addr),
+    );
+    for a in std::net::ToSocketAddrs::to_socket_addrs(addr)? {
+        match std::net::TcpStream::connect_timeout(&a, timeout) {
+            Ok(stream) => return Ok(stream),
+            Err(e) => last = e,
+        }
+    }
+    Err(last)
+}
+
+/// Take an advisory lock on `path` and write the pid into it, fails if another process holds the lock
+fn lock_exclusive(path: &str) -> Result<fs::File> {
+    use std::io::Read;
+    use std::io::Write;
+    let mut file = fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(path)
+        .map_err(Error::OpenLockFile)?;
+    match file.try_lock() {
+        Ok(()) => {
+            file.set_len(0)
+                .and_then(|_| writeln!(file, "{}", std::process::id()))
+                .map_err(Error::OpenLockFile)?;
+            log::debug!("holding the lock on {}", path);
+            Ok(file)
+        }
+        Err(fs::TryLockError::WouldBlock) => {
+            let mut pid = String::new();
+            let pid = file
+                .read_to_string(&mut pid)
+                .ok()
+                .and_then(|_| pid.trim().parse().ok());
+            Err(Error::Locked(path.to_owned(), pid))
+        }
+        Err(fs::TryLockError::Error(e)) => Err(Error::OpenLockFile(e)),
+    }
+}
+
+/// Random number, from the randomly seeded std hasher
+pub(crate) fn random() -> u64 {
+    use std::hash::BuildHasher;
+    use std::hash::Hasher;
+    std::collections::hash_map::RandomState::new()
+        .build_hasher()
+        .finish()
+}
+
+/// Every check must name a phase and every phase must have a check, if there are phases
+fn validate_phases(phases: &[Phase], checks: &[CheckSpec]) -> Result<()> {
+    if phases.is_empty() {
+        for spec in checks.iter().filter(|c| c.phase.is_some()) {
+            log::warn!(
+                "{}: there are no phases, the phase of the check is ignored",
+                spec
+            );
+        }
+        return Ok(());
+    }
+    if let Some(spec) = checks
+        .iter()
+        .find(|c| !phases.iter().any(|p| c.phase.as_ref() == Some(&p.name)))
+    {
+        return Err(Error::General(format!("{}: unknown phase", spec)));
+    }
+    for (i, phase) in phases.iter().enumerate() {
+        if phases[..i].iter().any(|p| p.name == phase.name) {
+            return Err(Error::General(format!(
+                "phase {} is given twice",
+                phase.name
+            )));
+        }
+        if !checks.iter().any(|c| c.phase.as_ref() == Some(&phase.name)) {
+            return Err(Error::General(format!(
+                "phase {} has no checks, add ;phase={} to the checks it should evaluate",
+                phase.name, phase.name
+            )));
+        }
+    }
+    Ok(())
+}
+
+/// The deadline of a phase starting now, never later than the overall deadline
+fn phase_deadline(overall: Option<Instant>, phase: Option<&Phase>) -> Option<Instant> {
+    match (overall, phase.map(|p| Instant::now() + p.budget)) {
+        (Some(overall), Some(phase)) => Some(overall.min(phase)),
+        (overall, phase) => overall.or(phase),
+    }
+}
+
+/// Report a failed wait to the observer and hand the error back
+fn finish_failed(observer: &mut dyn Observer, e: Error) -> Error {
+    let result = Err(e);
+    observer.finished(&result);
+    match result {
+        Err(e) => e,
+        Ok(()) => unreachable!(),
+    }
+}
+
+/// Format a duration like "4m32s"
+fn compact_duration(d: Duration) -> String {
+    let secs = d.as_secs();
+    match (secs / 3600, secs / 60 % 60, secs % 60) {
+        (0, 0, s) => format!("{}s", s),
+        (0, m, s) => format!("{}m{}s", m, s),
+        (h, m, s) => format!("{}h{}m{}s", h, m, s),
+    }
+}
+
+/// Log suffix with the time remaining until the global timeout, if there is one
+fn giving_up_in(deadline: Option<Instant>) -> String {
+    deadline
+        .map(|d| {
+            format!(
+                ", giving up in {}",
+                compact_duration(d.saturating_duration_since(Instant::now()))
+            )
+        })
+        .unwrap_or_default()
+}
+
+fn leader_stability(
+    leader: Option<String>,
+    stable_for: Option<Duration>,
+    window: Duration,
+) -> Outcome {
+    match (stable_for, leader) {
+        (Some(stable_for), Some(_)) if stable_for >= window => Outcome::Online,
+        (Some(stable_for), Some(leader)) => {
+            Outcome::NotReady(NotReadyReason::LeaderUnstable { leader, stable_for })
+        }
+        _ => Outcome::NotReady(NotReadyReason::NoLeader),
+    }
+}
+
+/// Online only if the check responded within `max` for the last `polls` times in a row.
+/// `fast` counts the consecutive polls that were fast enough so far
+fn latency_slo(
+    outcome: Outcome,
+    latency: Duration,
+    max: Duration,
+    polls: u32,
+    fast: &mut u32,
+) -> Outcome {
+    if !matches!(outcome, Outcome::Online) {
+        *fast = 0;
+        return outcome;
+    }
+    if latency > max {
+        *fast = 0;
+    } else {
+        *fast = fast.saturating_add(1);
+        if *fast >= polls {
+            return Outcome::Online;
+        }
+    }
+    Outcome::NotReady(NotReadyReason::Slow {
+        latency,
+        max,
+        fast: *fast,
+        polls,
+    })
+}
+
+/// Result of a single polling round
+#[derive(Debug)]
+pub enum PollOutcome {
+    /// Consul is online, the wait is over
+    Online(AgentInfo),
+    /// Consul is not online yet, poll again after `retry_in`
+    Pending { retry_in: Duration },
+    /// The wait failed, polling again is pointless
+    Failed(Error),
+}
+
+/// Waits for consul to come online one polling round at a time, for callers that drive their own loop.
+/// [wait] is a blocking loop around [Waiter::poll_once].
+/// A waiter should not be polled again once it returned [PollOutcome::Online] or [PollOutcome::Failed].
+pub struct Waiter {
+    config: Config,
+    /// Held until the waiter is dropped, with [Config::exclusive]
+    _lock: Option<fs::File>,
+    client: Client,
+    checks: Vec<CheckSpec>,
+    /// When each check with its own interval was evaluated last, and why it failed if it did
+    evaluated: Vec<Option<(Instant, Option<String>)>>,
+    /// Consecutive polls each check responded within [Config::max_latency]
+    fast_polls: Vec<u32>,
+    /// Index of the last response of each check, the next blocking query waits for a change past it
+    indexes: Vec<Option<u64>>,
+    /// Failing degraded checks of the last round in which all required checks passed, if it was the previous round
+    degraded: Option<Vec<String>>,
+    /// Why attempts were not online so far
+    history: ReasonHistory,
+    /// With [Config::state_file]: the path, and the hash of the configuration the state belongs to
+    state_file: Option<(String, String)>,
+    /// Why the token could not be read yet, with [Config::retry_token]
+    token_error: Option<Error>,
+    /// Time until the next attempt to read the token, doubles up to the interval
+    token_backoff: Duration,
+    builtin: Vec<Box<dyn Observer + Send>>,
+    /// When the wait started, earlier than this process if it was resumed from [Config::state_file]. Deadlines and
+    /// pauses are measured with the monotonic clock, so that the wall clock stepping doesn't affect them
+    start_time: Instant,
+    /// When the timeout or the budget of the current phase expires, whichever is first
+    phase_deadline: Option<Instant>,
+    /// When the timeout expires
+    overall_deadline: Option<Instant>,
+    phases: Vec<Phase>,
+    /// Index of the current phase and when it started
+    phase: (usize, Instant),
+    policy: RetryPolicy,
+    /// Shortest interval of the checks, rounds are at least this frequent
+    checks_interval: Option<Duration>,
+    attempts: u64,
+    /// Consecutive rounds that were not online, the policy backs off by these
+    failed_rounds: u32,
+    /// Checks that were warned about a permission the token lacks, to warn only once
+    denied: Vec<String>,
+    leaders: LeaderTracker,
+    catalog: IndexTracker,
+}
+
+impl Waiter {
+    pub fn new(config: Config) -> Result<Self> {
+        let builtin = builtin_observers(&config)?;
+        Self::with_observers(config, builtin)
+    }
+
+    fn with_observers(config: Config, mut builtin: Vec<Box<dyn Observer + Send>>) -> Result<Self> {
+        let conflicts = config.conflicts();
+        if config.strict_config && !conflicts.is_empty() {
+            return Err(finish_failed(
+                &mut builtin,
+                Error::ConfigConflicts(conflicts),
+            ));
+        }
+        for conflict in conflicts {
+            log::warn!("{}", conflict);
+        }
+        if let Some(reason) = config.forbid_insecure.then(|| config.insecure()).flatten() {
+            return Err(finish_failed(&mut builtin, Error::Insecure(reason)));
+        }
+        if let Some(reason) = config.no_token.then(|| config.token_required()).flatten() {
+            return Err(finish_failed(&mut builtin, Error::General(reason)));
+        }
+        if let Some(option) = config.hardened.then(|| config.needs_privileges()).flatten() {
+            return Err(finish_failed(
+                &mut builtin,
+                Error::General(format!("--hardened can't be used with {}", option)),
+            ));
+        }
+        let lock = match config.exclusive.as_deref().map(lock_exclusive).transpose() {
+            Ok(lock) => lock,
+            Err(e) => return Err(finish_failed(&mut builtin, e)),
+        };
+        let (client, token_error) = match Client::new(&config) {
+            Ok(client) => (client, None),
+            // poll without the token until it can be read
+            Err(e @ (Error::ReadTokenFile(_) | Error::ReadKeyring(_))) if config.retry_token => {
+                let tokenless = Config {
+                    http_token_file: None,
+                    http_token_keyring: None,
+                    ..config.clone()
+                };
+                match Client::new(&tokenless) {
+                    Ok(client) => (client, Some(e)),
+                    Err(e) => return Err(finish_failed(&mut builtin, e)),
+                }
+            }
+            Err(e) => return Err(finish_failed(&mut builtin, e)),
+        };
+        let checks = config.effective_checks();
+        if let Err(e) = validate_phases(&config.phases, &checks) {
+            return Err(finish_failed(&mut builtin, e));
+        }
+        let policy = config.retry_policy();
+        let state_file = config
+            .state_file
+            .clone()
+            .map(|path| (path, config.redacted_hash()));
+        let resumed = state_file
+            .as_ref()
+            .and_then(|(path, hash)| state::SavedState::load(path, hash));
+        // the state file is the only place the wall clock is needed, across processes there is no monotonic clock
+        let elapsed = resumed.as_ref().map_or(Duration::ZERO, |s| {
+            SystemTime::now()
+                .duration_since(s.started())
+                .unwrap_or_default()
+        });
+        let now = Instant::now();
+        let start_time = now.checked_sub(elapsed).unwrap_or(now);
+        if let (Some(resumed), Some((path, _))) = (&resumed, &state_file) {
+            log::info!(
+                "resuming the wait started {} ago after {} attempts, from {}",
+                compact_duration(elapsed),
+                resumed.attempts,
+                path
+            );
+        }
+        let overall_deadline = policy.timeout.map(|t| now + t.saturating_sub(elapsed));
+        if config.hardened {
+            if let Err(e) = harden::harden() {
+                return Err(finish_failed(&mut builtin, Error::Harden(e)));
+            }
+            log::debug!("hardened, no more writes to the filesystem");
+        }
+        if let Some(first) = config.phases.first() {
+            log::info!(
+                "phase {}, budget {}",
+                first.name,
+                humantime::format_duration(first.budget)
+            );
+        }
+        Ok(Self {
+            _lock: lock,
+            phase_deadline: phase_deadline(overall_deadline, config.phases.first()),
+            overall_deadline,
+            phases: config.phases.clone(),
+            phase: (0, Instant::now()),
+            checks_interval: checks.iter().filter_map(|c| c.interval).min(),
+            policy,
+            evaluated: vec![None; checks.len()],
+            fast_polls: vec![0; checks.len()],
+            indexes: vec![None; checks.len()],
+            degraded: None,
+            history: ReasonHistory::default(),
+            token_error,
+            token_backoff: Duration::from_secs(1),
+            config,
+            client,
+            checks,
+            builtin,
+            start_time,
+            attempts: resumed.map_or(0, |s| s.attempts),
+            state_file,
+            failed_rounds: 0,
+            denied: Vec::new(),
+            leaders: LeaderTracker::default(),
+            catalog: IndexTracker::default(),
+        })
+    }
+
+    /// Number of polling rounds so far
+    pub fn attempts(&self) -> u64 {
+        self.attempts
+    }
+
+    /// When the global timeout expires, if there is one
+    pub fn deadline(&self) -> Option<Instant> {
+        self.overall_deadline
+    }
+
+    /// When the current [Phase] runs out of its budget or the global timeout expires, whichever is first
+    pub fn phase_deadline(&self) -> Option<Instant> {
+        self.phase_deadline
+    }
+
+    /// Run a single polling round
+    pub fn poll_once(&mut self) -> PollOutcome {
+        self.poll_once_observed(&mut ())
+    }
+
+    /// Same as [Waiter::poll_once], but reports every attempt to the provided observer
+    pub fn poll_once_observed(&mut self, observer: &mut dyn Observer) -> PollOutcome {
+        let mut builtin = std::mem::take(&mut self.builtin);
+        let mut observers = (observer, &mut builtin);
+        let outcome = self.round(&mut observers);
+        if let Some((path, hash)) = &self.state_file {
+            match outcome {
+                PollOutcome::Pending { .. } => {
+                    state::SavedState::new(hash, self.start_time.elapsed(), self.attempts)
+                        .save(path)
+                }
+                _ => state::SavedState::remove(path),
+            }
+        }
+        let outcome = match outcome {
+            PollOutcome::Pending { .. } => outcome,
+            PollOutcome::Online(info) => {
+                observers.finished(&Ok(()));
+                PollOutcome::Online(info)
+            }
+            PollOutcome::Failed(e) => {
+                PollOutcome::Failed(finish_failed(&mut observers, self.in_phase(e)))
+            }
+        };
+        self.builtin = builtin;
+        outcome
+    }
+
+    /// The error with the phase it occurred in, if there are phases
+    fn in_phase(&self, e: Error) -> Error {
+        match self.phases.get(self.phase.0) {
+            Some(phase) => Error::Phase(phase.name.clone(), Box::new(e)),
+            None => e,
+        }
+    }
+
+    /// Move on to the next phase once the checks of the current one passed. Returns false in the last phase
+    fn next_phase(&mut self) -> bool {
+        let (current, started) = self.phase;
+        let next = match self.phases.get(current + 1) {
+            Some(next) => next,
+            None => return false,
+        };
+        log::info!(
+            "phase {} passed after {}, next phase {}, budget {}",
+            self.phases[current].name,
+            compact_duration(started.elapsed()),
+            next.name,
+            humantime::format_duration(next.budget)
+        );
+        self.phase_deadline = phase_deadline(self.overall_deadline, Some(next));
+        self.phase = (current + 1, Instant::now());
+        self.history = ReasonHistory::default();
+        self.degraded = None;
+        self.failed_rounds = 0;
+        true
+    }
+
+    /// Same as [Waiter::poll_once_observed], but the outcome is not final, the builtin observers are not finished
+    fn watch_round(&mut self, observer: &mut dyn Observer) -> PollOutcome {
+        let mut builtin = std::mem::take(&mut self.builtin);
+        let outcome = self.round(&mut (observer, &mut builtin));
+        self.builtin = builtin;
+        outcome
+    }
+
+    /// Report a state change to the observer and the builtin observers
+    fn report_change(&mut self, observer: &mut dyn Observer, transition: &Transition) {
+        observer.changed(transition);
+        self.builtin.changed(transition);
+    }
+
+    /// The policy the waiter polls with
+    pub fn policy(&self) -> &RetryPolicy {
+        &self.policy
+    }
+
+    /// Time between the current and the next round, the policy interval after backing off,
+    /// but not longer than the shortest interval of the checks
+    fn interval(&self) -> Duration {
+        let interval = self.policy.interval_after(self.failed_rounds);
+        self.checks_interval
+            .map_or(interval, |checks| interval.min(checks))
+    }
+
+    fn single_attempt(&self) -> bool {
+        self.policy.single_attempt()
+    }
+
+    /// The timeout expired or the maximum number of rounds was made
+    fn expired(&self) -> bool {
+        self.single_attempt()
+            || self.phase_deadline.is_some_and(|d| Instant::now() >= d)
+            || self
+                .policy
+                .max_attempts
+                .is_some_and(|max| self.attempts >= max)
+    }
+
+    /// Per request timeout for the next round
+    fn request_timeout(&self) -> Duration {
+        std::cmp::max(
+            self.phase_deadline
+                .filter(|_| !self.single_attempt())
+                .map(|deadline| {
                     std::cmp::min(
-                        Duration::from_secs(global_timeout)
-                            .checked_sub(SystemTime::now().duration_since(start_time).unwrap())
-                            .unwrap_or(Duration::from_secs(0)),
-                        interval,
+                        deadline.saturating_duration_since(Instant::now()),
+                        self.interval(),
                     )
                 })
-                .unwrap_or(interval),
-            if config.reconnect {
+                .unwrap_or(self.interval()),
+            if self.config.reconnect {
                 Duration::from_secs(0)
             } else {
                 Duration::from_secs(10)
             },
-        );
-        let req_start = SystemTime::now();
+        )
+    }
+
+    /// Ask for a token if none is configured, true if requests should be retried with it
+    fn prompt_token(&mut self) -> bool {
+        let config = &self.config;
+        if config.http_token.is_some()
+            || config.http_token_file.is_some()
+            || config.http_token_keyring.is_some()
+        {
+            return false;
+        }
+        let token = match config.token_prompt.as_ref().and_then(|prompt| prompt()) {
+            Some(token) if !token.is_empty() => token,
+            _ => return false,
+        };
+        self.config.http_token = Some(token);
+        match Client::new(&self.config) {
+            Ok(client) => {
+                log::info!("retry with the provided token");
+                self.client = client;
+                true
+            }
+            Err(e) => {
+                log::warn!("failed to use the provided token: {}", e);
+                false
+            }
+        }
+    }
+
+    /// With [Config::auto_scheme], poll with the other scheme from now on, once. True if requests should be retried
+    fn switch_scheme(&mut self, addr: &str, tls: bool) -> bool {
+        if !self.config.auto_scheme {
+            return false;
+        }
+        let scheme = |tls| if tls { "https" } else { "http" };
+        let config = Config {
+            http_addr: match self.config.http_addr.split_once("://") {
+                Some(("http" | "https", rest)) => format!("{}://{}", scheme(tls), rest),
+                Some(_) => self.config.http_addr.clone(),
+                None => format!("{}://{}", scheme(tls), self.config.http_addr),
+            },
+            http_ssl: tls,
+            auto_scheme: false,
+            ..self.config.clone()
+        };
+        match Client::new(&config) {
+            Ok(client) => {
+                log::warn!(
+                    "{} speaks {}, not {}: retry with {}, fix the address to skip this",
+                    addr,
+                    scheme(tls),
+                    scheme(!tls),
+                    redact_address(&config.http_addr)
+                );
+                self.client = client;
+                self.config = config;
+                true
+            }
+            Err(e) => {
+                log::warn!("failed to switch to {}: {}", scheme(tls), e);
+                self.config.auto_scheme = false;
+                false
+            }
+        }
+    }
+
+    /// Try to read the token again if it failed before. None if the round can go ahead
+    fn acquire_token(&mut self) -> Option<PollOutcome> {
+        self.token_error.as_ref()?;
+        match Client::new(&self.config) {
+            Ok(client) => {
+                log::info!("read the token, poll with it from now on");
+                self.client = client;
+                self.token_error = None;
+                None
+            }
+            Err(e) => {
+                if self.expired() {
+                    self.token_error = None;
+                    return Some(PollOutcome::Failed(e));
+                }
+                log::info!(
+                    "token not available yet, retry in {}: {}",
+                    compact_duration(self.token_backoff),
+                    e
+                );
+                let retry_in = self.token_backoff;
+                self.token_backoff = (self.token_backoff * 2).min(self.interval());
+                self.token_error = Some(e);
+                Some(PollOutcome::Pending { retry_in })
+            }
+        }
+    }
+
+    /// Look up the target of a srv:// address. None if the round can go ahead
+    fn lookup_srv(&mut self) -> Option<PollOutcome> {
+        let known = self.client.srv.as_ref().is_some_and(|s| s.target.is_some());
+        match self.client.lookup_srv(self.failed_rounds > 0) {
+            Ok(()) => None,
+            Err(e) if known => {
+                log::warn!("srv lookup failed, keep using the last target: {}", e);
+                None
+            }
+            Err(e) => {
+                self.attempts += 1;
+                if self.expired() || !self.config.reconnect {
+                    return Some(PollOutcome::Failed(e));
+                }
+                log::info!("{}{}", e, giving_up_in(self.phase_deadline));
+                self.failed_rounds = self.failed_rounds.saturating_add(1);
+                Some(PollOutcome::Pending {
+                    retry_in: self.policy.jittered(self.interval()),
+                })
+            }
+        }
+    }
+
+    fn round(&mut self, observer: &mut dyn Observer) -> PollOutcome {
+        if let Some(outcome) = self.acquire_token() {
+            return outcome;
+        }
+        if let Some(outcome) = self.lookup_srv() {
+            return outcome;
+        }
+        log::debug!("request...");
+        let timeout = self.request_timeout();
+        let round_start = Instant::now();
         log::info!("will timeout after {} millis", timeout.as_millis());
-        match do_request(&agent, url.as_str(), timeout, &header_adder) {
-            Ok(code) => match code {
-                200 => break Ok(()),
-                _ => {
-                    log::info!("code: {}", code);
+        self.attempts += 1;
+        let address = self.client.probe(timeout);
+        let single_attempt = self.single_attempt();
+        let round_interval = self.interval();
+        let config = &self.config;
+        let client = &self.client;
+        let leaders = &mut self.leaders;
+        let mut online = true;
+        let mut terminal = None;
+        let mut acl_denied = false;
+        let mut last_peer = None;
+        // failing checks by severity, a round with only failing degraded checks may end up as Error::Degraded
+        let mut required_failed = false;
+        // whether each required check passed, for the success policy
+        let mut required = Vec::new();
+        let mut degraded = Vec::new();
+        let mut degraded_request_failed = false;
+        // whether all failing checks make blocking queries in the next round, which wait for changes by themselves
+        let mut next_round_blocks = config.blocking_queries;
+        // a blocking query waits at most for the polling interval, and not past the deadline
+        let wait = self.phase_deadline.map_or(round_interval, |d| {
+            round_interval.min(d.saturating_duration_since(Instant::now()))
+        });
+        let phase = self.phases.get(self.phase.0).map(|p| &p.name);
+        for (i, spec) in self.checks.iter().enumerate() {
+            if phase.is_some() && spec.phase.as_ref() != phase {
+                continue;
+            }
+            let check = &spec.check;
+            if let Some((at, failure)) = &self.evaluated[i] {
+                // rounds don't start at exact intervals, so a check is due within half a round of its interval
+                if spec
+                    .interval
+                    .is_some_and(|interval| at.elapsed() + round_interval / 2 < interval)
+                {
+                    log::debug!(
+                        "{}: not due yet, last result: {}",
+                        check,
+                        failure.as_deref().unwrap_or("online")
+                    );
+                    if spec.severity == Severity::Required {
+                        required.push(failure.is_none());
+                    }
+                    if let Some(reason) = failure {
+                        match spec.severity {
+                            Severity::Required => required_failed = true,
+                            Severity::Degraded => degraded.push(format!("{}: {}", check, reason)),
+                        }
+                        online = false;
+                    }
+                    continue;
                 }
-            },
-            Err(err) => match err {
-                Error::Request(ureq::Error::Status(s, r)) => {
-                    if s == 500 {
-                        log::info!("not ready yet: {}/{}", r.status_text(), s);
-                    } else if !config.reconnect {
-                        break Err(Error::Request(ureq::Error::Status(s, r)));
-                    } else {
-                        log::info!("request failed: {}", s);
+            }
+            let mut timeout = spec.timeout.unwrap_or(timeout);
+            let blocking = config.blocking_queries && check.supports_blocking();
+            if let (true, Some(index)) = (blocking, self.indexes[i]) {
+                client.block(index, wait);
+                // the agent adds up to a sixteenth of the wait time to spread out responses
+                timeout += wait + wait / 16;
+            }
+            let req_start = SystemTime::now();
+            let evaluated_at = Instant::now();
+            let mut leader = None;
+            let evaluation = match check {
+                Check::Raft | Check::Leader => {
+                    let mut evaluation = checks::evaluate(check, client, timeout);
+                    leader = evaluation
+                        .reply
+                        .as_ref()
+                        .filter(|reply| reply.status == 200)
+                        .and_then(|reply| match check {
+                            Check::Leader => checks::leader_address(&reply.body),
+                            _ => RaftConfiguration::parse(&reply.body)
+                                .and_then(|raft| raft.leader().map(|l| l.address.clone())),
+                        });
+                    if config.require_leader
+                        && leader.is_none()
+                        && matches!(evaluation.outcome, Outcome::Online)
+                    {
+                        evaluation.outcome = Outcome::NotReady(NotReadyReason::NoLeader);
                     }
+                    let changes_before = leaders.changes();
+                    let stable_for = leaders.observe(leader.as_deref());
+                    if config.max_leader_changes.is_some_and(|max| {
+                        leaders.changes() > max && leaders.changes() > changes_before
+                    }) {
+                        log::warn!(
+                            "raft leader changed {} times, the cluster might be unstable",
+                            leaders.changes()
+                        );
+                        if config.fail_on_flapping {
+                            terminal = Some(Error::LeaderFlapping(leaders.changes()));
+                        }
+                    }
+                    if let (Outcome::Online, Some(window)) =
+                        (&evaluation.outcome, self.policy.stability_window)
+                    {
+                        evaluation.outcome = leader_stability(leader.clone(), stable_for, window);
+                    }
+                    evaluation
+                }
+                Check::CatalogStable(window) => {
+                    checks::catalog_stable(*window, client, timeout, &mut self.catalog)
                 }
-                rest => {
-                    if !config.reconnect {
-                        break Err(rest);
-                    } else {
-                        log::info!("request failed: {}", rest);
+                check => checks::evaluate(check, client, timeout),
+            };
+            client.unblock();
+            let Evaluation {
+                target,
+                reply,
+                error,
+                outcome,
+            } = evaluation;
+            if blocking {
+                // an index that is not past zero must not be used for blocking
+                self.indexes[i] = reply.as_ref().and_then(|r| r.index).filter(|i| *i > 0);
+            }
+            let latency = evaluated_at.elapsed();
+            let outcome = match (outcome, &reply) {
+                (Outcome::Online, Some(reply)) => config
+                    .expect_headers
+                    .iter()
+                    .find_map(|expected| expected.mismatch(&reply.headers))
+                    .map_or(Outcome::Online, |mismatch| {
+                        Outcome::NotReady(NotReadyReason::Unsatisfied(mismatch))
+                    }),
+                (outcome, _) => outcome,
+            };
+            let outcome = match config.max_latency {
+                Some(max) => latency_slo(
+                    outcome,
+                    latency,
+                    max,
+                    config.max_latency_polls,
+                    &mut self.fast_polls[i],
+                ),
+                None => outcome,
+            };
+            // without a response, the request went to the probed address if there was one
+            let peer = reply.as_ref().and_then(|r| r.peer).or(address);
+            last_peer = peer.or(last_peer);
+            let via = peer.map(|p| format!(" ({})", p)).unwrap_or_default();
+            let event = Attempt {
+                number: self.attempts,
+                check,
+                timestamp: req_start,
+                target: target.as_str(),
+                latency,
+                status: reply.as_ref().map(|r| r.status),
+                headers: reply.as_ref().map_or(&[], |r| r.headers.as_slice()),
+                body: reply.as_ref().map(|r| r.body.as_str()),
+                leader: leader.as_deref(),
+                leader_changes: leaders.changes(),
+                peer,
+                outcome,
+            };
+            observer.attempt(&event);
+            self.history.record(&event);
+            if let (Some(body), Some(_), true) = (
+                event.body,
+                event.outcome.reason(),
+                config.log_body_bytes > 0,
+            ) {
+                log::debug!(
+                    "{}: response body ({} of {} bytes): {}",
+                    check,
+                    body.len().min(config.log_body_bytes),
+                    body.len(),
+                    body_prefix(body, config.log_body_bytes)
+                );
+            }
+            let failed_because = event.outcome.reason().map(|r| r.to_string());
+            if spec.interval.is_some() {
+                self.evaluated[i] = Some((evaluated_at, failed_because.clone()));
+            }
+            if spec.severity == Severity::Required {
+                required.push(failed_because.is_none());
+            }
+            if let Some(reason) = failed_because {
+                match spec.severity {
+                    Severity::Required => required_failed = true,
+                    Severity::Degraded => degraded.push(format!("{}: {}", check, reason)),
+                }
+            }
+            if event.outcome.reason().is_some() && !(blocking && self.indexes[i].is_some()) {
+                next_round_blocks = false;
+            }
+            if let Some(NotReadyReason::PermissionDenied { permission }) = event.outcome.reason() {
+                let check = check.to_string();
+                if !self.denied.contains(&check) {
+                    match permission {
+                        Some(p) => log::warn!("{}: the acl token lacks permission {}", check, p),
+                        None => log::warn!(
+                            "{}: the acl token lacks a permission the check needs",
+                            check
+                        ),
                     }
+                    self.denied.push(check);
                 }
-            },
+            }
+            acl_denied |= matches!(&reply, Some(r) if r.status == 403 && !r.body.contains("must be bootstrapped"));
+            match event.outcome {
+                Outcome::Online => continue,
+                Outcome::NotReady(reason) => {
+                    log::info!(
+                        "{}{}: not ready yet: {}{}",
+                        check,
+                        via,
+                        reason,
+                        giving_up_in(self.phase_deadline)
+                    )
+                }
+                Outcome::Failed(reason) => {
+                    log::info!(
+                        "{}{}: request failed: {}{}",
+                        check,
+                        via,
+                        reason,
+                        giving_up_in(self.phase_deadline)
+                    );
+                    // neither a mismatching acl configuration nor a port that doesn't serve http fix themselves by reconnecting
+                    let fatal = matches!(
+                        error,
+                        Some(Error::AclMismatch(_) | Error::NotHttp(..) | Error::WrongScheme(..))
+                    );
+                    if spec.severity == Severity::Degraded {
+                        degraded_request_failed = true;
+                    } else if (fatal || single_attempt || !config.reconnect) && terminal.is_none() {
+                        terminal = Some(failure(&target, reply, error));
+                    }
+                }
+            }
+            online = false;
+        }
+        // with the checks policy "any", one passing required check is enough
+        if !required.is_empty() {
+            required_failed = !config.success_policy.evaluate(&[required]);
+            online = !required_failed && degraded.is_empty();
+        }
+        if acl_denied && self.prompt_token() {
+            return PollOutcome::Pending {
+                retry_in: Duration::ZERO,
+            };
         }
-        if let Some(timeout) = config.timeout {
-            let now = SystemTime::now();
-            if start_time + std::time::Duration::from_secs(timeout) < now {
-                break Err(Error::Timeout(now.duration_since(start_time).unwrap()));
+        if let Some(Error::WrongScheme(addr, tls)) = &terminal {
+            if self.switch_scheme(addr, *tls) {
+                return PollOutcome::Pending {
+                    retry_in: Duration::ZERO,
+                };
             }
         }
-        if let Some(d) = timeout.checked_sub(SystemTime::now().duration_since(req_start).unwrap()) {
-            log::debug!("sleep {} millis", d.as_millis());
-            std::thread::sleep(d)
+        if let Some(err) = terminal {
+            return PollOutcome::Failed(err);
+        }
+        if online && self.next_phase() {
+            return PollOutcome::Pending {
+                retry_in: Duration::ZERO,
+            };
+        }
+        if online {
+            self.failed_rounds = 0;
+            let info = AgentInfo {
+                address: last_peer.map(|a| a.to_string()),
+                ..agent_info(&self.client, timeout)
+            };
+            log::info!(
+                "leader: {}, version: {}",
+                info.leader.as_deref().unwrap_or("unknown"),
+                info.version.as_deref().unwrap_or("unknown")
+            );
+            observer.online(&info);
+            return PollOutcome::Online(info);
+        }
+        let expired = self.expired();
+        // the last round before the deadline has little time for its requests, it is enough if the previous round
+        // was degraded
+        let previous = self.degraded.take();
+        if !required_failed && !degraded.is_empty() {
+            // only degraded checks fail and waiting is over, or won't help because requests of degraded checks failed
+            if expired || (degraded_request_failed && !self.config.reconnect) {
+                return PollOutcome::Failed(Error::Degraded(degraded));
+            }
+            self.degraded = Some(degraded);
+        } else if let (true, Some(previous)) = (expired, previous) {
+            return PollOutcome::Failed(Error::Degraded(previous));
+        }
+        if expired {
+            if let Some(max) = self.policy.max_attempts.filter(|max| self.attempts >= *max) {
+                log::warn!("giving up after {} attempts", max);
+            }
+            return PollOutcome::Failed(Error::Timeout(
+                self.start_time.elapsed(),
+                std::mem::take(&mut self.history),
+            ));
+        }
+        self.failed_rounds = self.failed_rounds.saturating_add(1);
+        if next_round_blocks {
+            log::debug!("next round waits for changes with blocking queries");
+            return PollOutcome::Pending {
+                retry_in: Duration::ZERO,
+            };
+        }
+        let pause = self.policy.jittered(timeout);
+        PollOutcome::Pending {
+            retry_in: pause.saturating_sub(round_start.elapsed()).min(
+                self.phase_deadline.map_or(Duration::MAX, |d| {
+                    d.saturating_duration_since(Instant::now())
+                }),
+            ),
         }
     }
 }
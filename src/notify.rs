@@ -0,0 +1,59 @@
+use std::time::Duration;
+
+use crate::events::AgentInfo;
+use crate::events::Attempt;
+use crate::events::Observer;
+use crate::report::Report;
+use crate::Error;
+
+/// POSTs the final result as json to a webhook
+pub struct Notifier {
+    url: String,
+    token: Option<String>,
+    retries: u32,
+    report: Report,
+}
+
+impl Notifier {
+    pub fn new(url: &str, token: Option<&str>, retries: u32) -> Self {
+        Self {
+            url: url.to_owned(),
+            token: token.map(str::to_owned),
+            retries,
+            report: Report::new(),
+        }
+    }
+}
+
+impl Observer for Notifier {
+    fn attempt(&mut self, attempt: &Attempt) {
+        self.report.attempt(attempt);
+    }
+
+    fn online(&mut self, info: &AgentInfo) {
+        self.report.online(info);
+    }
+
+    fn finished(&mut self, result: &std::result::Result<(), Error>) {
+        let body = self.report.to_json(result).to_string();
+        for attempt in 0..=self.retries {
+            if attempt > 0 {
+                std::thread::sleep(Duration::from_secs(attempt as u64));
+            }
+            let mut request = ureq::post(self.url.as_str())
+                .timeout(Duration::from_secs(10))
+                .set("Content-Type", "application/json");
+            if let Some(token) = &self.token {
+                request = request.set("Authorization", format!("Bearer {}", token).as_str());
+            }
+            match request.send_string(body.as_str()) {
+                Ok(_) => {
+                    log::info!("sent result to {}", self.url);
+                    return;
+                }
+                Err(e) => log::warn!("failed to send result to {}: {}", self.url, e),
+            }
+        }
+        log::error!("giving up sending result to {}", self.url);
+    }
+}
@@ -0,0 +1,175 @@
+use std::fmt::Display;
+use std::str::FromStr;
+use std::time::Duration;
+
+/// When to poll and when to give up.
+/// Built from [crate::Config::timeout], [crate::Config::interval] and [crate::Config::leader_stable_for] unless
+/// [crate::Config::retry] is set
+#[derive(Debug, Clone, PartialEq)]
+pub struct RetryPolicy {
+    /// Time between the start of two rounds
+    pub interval: Duration,
+    /// Give up after this long, never if None. Zero means: make exactly one attempt
+    pub timeout: Option<Duration>,
+    /// Factor the interval grows by after every round that was not online, 1 keeps it fixed
+    pub backoff: f64,
+    /// Upper bound of the interval while backing off
+    pub max_interval: Duration,
+    /// Fraction of the interval the pause between rounds is randomly shortened or extended by, between 0 and 1
+    pub jitter: f64,
+    /// Give up after this many rounds, never if None
+    pub max_attempts: Option<u64>,
+    /// How long the raft leader must not change before consul is considered online
+    pub stability_window: Option<Duration>,
+}
+
+impl Default for RetryPolicy {
+    /// Same defaults as the command line: every 10 seconds, forever
+    fn default() -> Self {
+        Self::fixed(Duration::from_secs(10))
+    }
+}
+
+impl RetryPolicy {
+    /// Poll every `interval`
+    pub fn fixed(interval: Duration) -> Self {
+        Self {
+            interval,
+            timeout: None,
+            backoff: 1.0,
+            max_interval: interval,
+            jitter: 0.0,
+            max_attempts: None,
+            stability_window: None,
+        }
+    }
+
+    /// Start with `initial` and multiply the interval by `factor` after every round, up to `max`
+    pub fn exponential(initial: Duration, factor: f64, max: Duration) -> Self {
+        Self {
+            backoff: factor,
+            max_interval: max.max(initial),
+            ..Self::fixed(initial)
+        }
+    }
+
+    pub fn with_timeout(self, timeout: Duration) -> Self {
+        Self {
+            timeout: Some(timeout),
+            ..self
+        }
+    }
+
+    pub fn with_jitter(self, jitter: f64) -> Self {
+        Self { jitter, ..self }
+    }
+
+    pub fn with_max_attempts(self, max_attempts: u64) -> Self {
+        Self {
+            max_attempts: Some(max_attempts),
+            ..self
+        }
+    }
+
+    pub fn with_stability_window(self, window: Duration) -> Self {
+        Self {
+            stability_window: Some(window),
+            ..self
+        }
+    }
+
+    /// Exactly one round is made
+    pub fn single_attempt(&self) -> bool {
+        self.timeout == Some(Duration::ZERO) || self.max_attempts == Some(1)
+    }
+
+    /// Interval after `failed` consecutive rounds that were not online
+    pub fn interval_after(&self, failed: u32) -> Duration {
+        if self.backoff <= 1.0 || failed == 0 {
+            return self.interval;
+        }
+        let factor = self.backoff.powi(failed.min(i32::MAX as u32) as i32);
+        Duration::try_from_secs_f64(self.interval.as_secs_f64() * factor)
+            .unwrap_or(Duration::MAX)
+            .min(self.max_interval)
+    }
+
+    /// `interval` randomly shortened or extended by [RetryPolicy::jitter]
+    pub fn jittered(&self, interval: Duration) -> Duration {
+        let jitter = self.jitter.clamp(0.0, 1.0);
+        if jitter == 0.0 {
+            return interval;
+        }
+        // uniform in [-1, 1]
+        let unit = (crate::random() >> 11) as f64 / (1u64 << 53) as f64 * 2.0 - 1.0;
+        interval.mul_f64(1.0 + jitter * unit)
+    }
+}
+
+/// Whether all or any one of several results must pass
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Quantifier {
+    #[default]
+    All,
+    Any,
+}
+
+impl Quantifier {
+    /// All results pass, or at least one does. All of no results pass, any of them don't
+    pub fn holds(self, results: impl IntoIterator<Item = bool>) -> bool {
+        let mut results = results.into_iter();
+        match self {
+            Quantifier::All => results.all(|passed| passed),
+            Quantifier::Any => results.any(|passed| passed),
+        }
+    }
+}
+
+impl FromStr for Quantifier {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "all" => Ok(Quantifier::All),
+            "any" => Ok(Quantifier::Any),
+            _ => Err(format!("policy must be all or any: {}", s)),
+        }
+    }
+}
+
+impl Display for Quantifier {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Quantifier::All => write!(f, "all"),
+            Quantifier::Any => write!(f, "any"),
+        }
+    }
+}
+
+/// When a round is online, given which checks passed on which target: by default, all checks must pass on any
+/// one of the targets
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SuccessPolicy {
+    pub targets: Quantifier,
+    pub checks: Quantifier,
+}
+
+impl Default for SuccessPolicy {
+    fn default() -> Self {
+        Self {
+            targets: Quantifier::Any,
+            checks: Quantifier::All,
+        }
+    }
+}
+
+impl SuccessPolicy {
+    /// Evaluate the results of the checks, one row per target
+    pub fn evaluate<R: AsRef<[bool]>>(&self, matrix: &[R]) -> bool {
+        self.targets.holds(
+            matrix
+                .iter()
+                .map(|row| self.checks.holds(row.as_ref().iter().copied())),
+        )
+    }
+}
@@ -0,0 +1,170 @@
+use std::collections::HashMap;
+use std::io;
+use std::net::SocketAddr;
+use std::net::TcpStream;
+use std::net::ToSocketAddrs;
+use std::sync::mpsc;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// Remembers which of the addresses of the agent answered first in the last round.
+/// Used as the resolver of the http agent, so requests go to that address instead of trying dead ones in order.
+#[derive(Clone)]
+pub(crate) struct PreferredAddress {
+    preferred: Arc<Mutex<Option<(String, SocketAddr)>>>,
+    /// Addresses known for a netloc without asking the resolver, like those of an SRV target
+    hints: Arc<Mutex<HashMap<String, Vec<SocketAddr>>>>,
+    resolver: Arc<dyn Resolver>,
+}
+
+impl PreferredAddress {
+    pub fn new(resolver: Arc<dyn Resolver>) -> Self {
+        Self {
+            preferred: Default::default(),
+            hints: Default::default(),
+            resolver,
+        }
+    }
+
+    /// Resolve `netloc` ("host:port"), the preferred address takes precedence if it was probed for `netloc`
+    pub fn resolve(&self, netloc: &str) -> io::Result<Vec<SocketAddr>> {
+        match &*self.preferred.lock().unwrap_or_else(|e| e.into_inner()) {
+            Some((probed, addr)) if probed == netloc => Ok(vec![*addr]),
+            _ => self.lookup(netloc),
+        }
+    }
+
+    /// Resolve `netloc` to `addrs` from now on, instead of asking the resolver
+    #[cfg(feature = "native-transport")]
+    pub fn hint(&self, netloc: &str, addrs: Vec<SocketAddr>) {
+        let mut hints = self.hints.lock().unwrap_or_else(|e| e.into_inner());
+        match addrs.is_empty() {
+            true => hints.remove(netloc),
+            false => hints.insert(netloc.to_owned(), addrs),
+        };
+    }
+
+    fn lookup(&self, netloc: &str) -> io::Result<Vec<SocketAddr>> {
+        let hinted = self
+            .hints
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .get(netloc)
+            .cloned();
+        match hinted {
+            Some(addrs) => Ok(addrs),
+            None => self.resolver.resolve(netloc),
+        }
+    }
+
+    /// Connect to all addresses `netloc` resolves to at the same time and prefer the first one that accepts.
+    /// Returns that address, or None if the name resolves to a single address or none accepted in time.
+    pub fn probe(&self, netloc: &str, timeout: Duration) -> Option<SocketAddr> {
+        self.set(None);
+        let addrs = match self.lookup(netloc) {
+            Ok(addrs) => addrs,
+            Err(e) => {
+                log::debug!("failed to resolve {}: {}", netloc, e);
+                return None;
+            }
+        };
+        if addrs.len() < 2 {
+            return None;
+        }
+        let timeout = timeout.max(Duration::from_millis(100));
+        let (tx, rx) = mpsc::channel();
+        for addr in addrs.iter().copied() {
+            let tx = tx.clone();
+            std::thread::spawn(move || match TcpStream::connect_timeout(&addr, timeout) {
+                Ok(_) => drop(tx.send(addr)),
+                Err(e) => log::debug!("probe {}: {}", addr, e),
+            });
+        }
+        drop(tx);
+        match rx.recv_timeout(timeout) {
+            Ok(addr) => {
+                log::info!(
+                    "{} resolves to {} addresses, {} answered first",
+                    netloc,
+                    addrs.len(),
+                    addr
+                );
+                self.set(Some((netloc.to_owned(), addr)));
+                Some(addr)
+            }
+            Err(_) => {
+                log::info!(
+                    "none of the {} addresses of {} accepted a connection",
+                    addrs.len(),
+                    netloc
+                );
+                None
+            }
+        }
+    }
+
+    fn set(&self, preferred: Option<(String, SocketAddr)>) {
+        *self.preferred.lock().unwrap_or_else(|e| e.into_inner()) = preferred;
+    }
+}
+
+/// Resolves "host:port" to socket addresses
+pub(crate) trait Resolver: Send + Sync {
+    fn resolve(&self, netloc: &str) -> io::Result<Vec<SocketAddr>>;
+}
+
+/// A lookup that did not finish in time, the source of the [io::Error] resolvers fail with then
+#[derive(Debug)]
+pub(crate) struct LookupTimeout {
+    pub netloc: String,
+    pub after: Duration,
+}
+
+impl std::fmt::Display for LookupTimeout {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "resolving {} timed out after {}",
+            self.netloc,
+            humantime::format_duration(self.after)
+        )
+    }
+}
+
+impl std::error::Error for LookupTimeout {}
+
+pub(crate) fn timed_out(netloc: &str, after: Duration) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::TimedOut,
+        LookupTimeout {
+            netloc: netloc.to_owned(),
+            after,
+        },
+    )
+}
+
+/// Resolves with the resolver of the operating system, giving up after `timeout`
+pub(crate) struct SystemResolver {
+    timeout: Duration,
+}
+
+impl SystemResolver {
+    pub fn new(timeout: Duration) -> Self {
+        Self { timeout }
+    }
+}
+
+impl Resolver for SystemResolver {
+    fn resolve(&self, netloc: &str) -> io::Result<Vec<SocketAddr>> {
+        if let Ok(addr) = netloc.parse::<SocketAddr>() {
+            return Ok(vec![addr]);
+        }
+        // the system resolver can't be interrupted, a stuck lookup keeps its thread until it gives up by itself
+        let (tx, rx) = mpsc::channel();
+        let owned = netloc.to_owned();
+        std::thread::spawn(move || drop(tx.send(owned.to_socket_addrs().map(Iterator::collect))));
+        rx.recv_timeout(self.timeout)
+            .unwrap_or_else(|_| Err(timed_out(netloc, self.timeout)))
+    }
+}
@@ -0,0 +1,56 @@
+use std::fs::OpenOptions;
+use std::io::Write;
+
+use crate::events::Attempt;
+use crate::events::Observer;
+use crate::Error;
+use crate::Result;
+
+/// Destination of the progress stream that stands for stderr
+pub const STDERR: &str = "-";
+
+/// Writes one json line per attempt to stderr or a file, as the wait progresses
+pub struct Progress {
+    out: Box<dyn Write + Send>,
+}
+
+impl Progress {
+    /// Write to the file at `path`, or to stderr if the path is [STDERR]
+    pub fn open(path: &str) -> Result<Self> {
+        let out: Box<dyn Write + Send> = match path {
+            STDERR => Box::new(std::io::stderr()),
+            path => Box::new(
+                OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(path)
+                    .map_err(Error::OpenProgress)?,
+            ),
+        };
+        Ok(Self { out })
+    }
+}
+
+impl Observer for Progress {
+    fn attempt(&mut self, attempt: &Attempt) {
+        let mut line = serde_json::json!({
+            "timestamp": humantime::format_rfc3339_millis(attempt.timestamp).to_string(),
+            "attempt": attempt.number,
+            "check": attempt.check.to_string(),
+            "outcome": attempt.outcome.name(),
+            "status": attempt.status,
+            "latency_ms": attempt.latency.as_millis() as u64,
+            "error": attempt.outcome.reason().map(|r| r.kind()),
+            "detail": attempt.outcome.reason().map(|r| r.to_string()),
+        })
+        .to_string();
+        line.push('\n');
+        if let Err(e) = self
+            .out
+            .write_all(line.as_bytes())
+            .and_then(|_| self.out.flush())
+        {
+            log::warn!("failed to write progress: {}", e);
+        }
+    }
+}
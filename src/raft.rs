@@ -0,0 +1,113 @@
+use std::fmt::Display;
+use std::time::Duration;
+use std::time::Instant;
+
+use serde::Deserialize;
+use serde::Serialize;
+
+/// A server as reported by /v1/operator/raft/configuration
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct RaftServer {
+    #[serde(rename = "ID")]
+    pub id: String,
+    pub node: String,
+    pub address: String,
+    pub leader: bool,
+    pub voter: bool,
+}
+
+/// Response of /v1/operator/raft/configuration
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct RaftConfiguration {
+    pub servers: Vec<RaftServer>,
+    pub index: u64,
+}
+
+impl RaftConfiguration {
+    pub fn parse(body: &str) -> Option<Self> {
+        serde_json::from_str(body)
+            .map_err(|e| log::debug!("failed to parse raft configuration: {}", e))
+            .ok()
+    }
+
+    pub fn leader(&self) -> Option<&RaftServer> {
+        self.servers.iter().find(|s| s.leader)
+    }
+}
+
+/// Formats the configuration like `consul operator raft list-peers`
+impl Display for RaftConfiguration {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let rows: Vec<[&str; 5]> = self
+            .servers
+            .iter()
+            .map(|s| {
+                [
+                    s.node.as_str(),
+                    s.id.as_str(),
+                    s.address.as_str(),
+                    if s.leader { "leader" } else { "follower" },
+                    if s.voter { "true" } else { "false" },
+                ]
+            })
+            .collect();
+        let header = ["Node", "ID", "Address", "State", "Voter"];
+        let widths: Vec<usize> = (0..header.len())
+            .map(|i| {
+                rows.iter()
+                    .map(|r| r[i].len())
+                    .chain(std::iter::once(header[i].len()))
+                    .max()
+                    .unwrap_or_default()
+            })
+            .collect();
+        for row in std::iter::once(&header).chain(rows.iter()) {
+            let line = row
+                .iter()
+                .zip(widths.iter())
+                .map(|(col, w)| format!("{:<w$}", col, w = w))
+                .collect::<Vec<_>>()
+                .join("  ");
+            writeln!(f, "{}", line.trim_end())?;
+        }
+        Ok(())
+    }
+}
+
+/// Tracks for how long the raft leader has been unchanged, and how often it changed
+#[derive(Default)]
+pub struct LeaderTracker {
+    current: Option<(String, Instant)>,
+    last: Option<String>,
+    changes: u32,
+}
+
+impl LeaderTracker {
+    /// Record the currently reported leader, returns for how long it has been the leader
+    pub fn observe(&mut self, leader: Option<&str>) -> Option<Duration> {
+        if let Some(leader) = leader {
+            if self.last.as_deref().is_some_and(|last| last != leader) {
+                self.changes += 1;
+            }
+            self.last = Some(leader.to_owned());
+        }
+        match (leader, &self.current) {
+            (Some(leader), Some((current, since))) if current == leader => Some(since.elapsed()),
+            (Some(leader), _) => {
+                self.current = Some((leader.to_owned(), Instant::now()));
+                Some(Duration::ZERO)
+            }
+            (None, _) => {
+                self.current = None;
+                None
+            }
+        }
+    }
+
+    /// Number of times a different leader was observed
+    pub fn changes(&self) -> u32 {
+        self.changes
+    }
+}
@@ -0,0 +1,184 @@
+use std::fs;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::time::Instant;
+use std::time::SystemTime;
+
+use crate::checks::Check;
+use crate::events::AgentInfo;
+use crate::events::Attempt;
+use crate::events::NotReadyReason;
+use crate::events::Observer;
+use crate::events::Outcome;
+use crate::events::ReasonHistory;
+use crate::raft::RaftConfiguration;
+use crate::Config;
+use crate::ConfigConflict;
+use crate::Error;
+
+/// Collects a machine-readable summary of a wait
+pub struct Report {
+    start: Instant,
+    attempts: u64,
+    target: Option<String>,
+    last_status: Option<u16>,
+    /// Check, kind and message of the last attempt that was not online
+    last_error: Option<(String, &'static str, String)>,
+    /// Permission the acl token lacked in the last attempt that was denied, empty if the agent did not name it
+    missing_permission: Option<String>,
+    online_body: Option<String>,
+    leader_changes: u32,
+    peer: Option<SocketAddr>,
+    history: ReasonHistory,
+    info: AgentInfo,
+    conflicts: Vec<ConfigConflict>,
+}
+
+impl Default for Report {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Report {
+    pub fn new() -> Self {
+        Self {
+            start: Instant::now(),
+            attempts: 0,
+            target: None,
+            last_status: None,
+            last_error: None,
+            missing_permission: None,
+            online_body: None,
+            leader_changes: 0,
+            peer: None,
+            history: ReasonHistory::default(),
+            info: AgentInfo::default(),
+            conflicts: Vec::new(),
+        }
+    }
+
+    /// Include the conflicts of the configuration that is waited with
+    pub fn config(&mut self, config: &Config) {
+        self.conflicts = config.conflicts();
+    }
+
+    /// Raft configuration reported by the successful attempt
+    pub fn raft_configuration(&self) -> Option<RaftConfiguration> {
+        self.online_body
+            .as_deref()
+            .and_then(RaftConfiguration::parse)
+    }
+
+    pub fn to_json(&self, result: &std::result::Result<(), Error>) -> serde_json::Value {
+        let error = result.as_ref().err().map(Error::without_phase);
+        serde_json::json!({
+            "ready": result.is_ok(),
+            "status": match error {
+                None => "ready",
+                Some(Error::Degraded(_)) => "degraded",
+                Some(_) => "not-ready",
+            },
+            "degraded_checks": match error {
+                Some(Error::Degraded(failing)) => Some(failing),
+                _ => None,
+            },
+            "phase": match result {
+                Err(Error::Phase(name, _)) => Some(name),
+                _ => None,
+            },
+            "elapsed_seconds": self.start.elapsed().as_secs_f64(),
+            "attempts": self.attempts,
+            "target": self.target,
+            "last_status": self.last_status,
+            "last_error": self.last_error.as_ref().map(|(check, kind, message)| serde_json::json!({
+                "check": check,
+                "reason": kind,
+                "message": message,
+            })),
+            "missing_permission": self.missing_permission,
+            "leader": self.info.leader,
+            "leader_changes": self.leader_changes,
+            "version": self.info.version,
+            "address": self.peer.map(|p| p.to_string()),
+            "error": result.as_ref().err().map(|e| e.to_string()),
+            "reasons": self.history.to_json(),
+            "conflicts": self.conflicts.iter().map(ConfigConflict::to_json).collect::<Vec<_>>(),
+        })
+    }
+
+    /// The result in the prometheus text format, for the textfile collector of the node exporter
+    pub fn to_prometheus(&self, result: &std::result::Result<(), Error>) -> String {
+        let now = SystemTime::now();
+        let metrics = [
+            (
+                "consul_online_success",
+                "Whether consul was online in time",
+                result.is_ok() as u8 as f64,
+            ),
+            (
+                "consul_online_duration_seconds",
+                "Time spent waiting for consul",
+                self.start.elapsed().as_secs_f64(),
+            ),
+            (
+                "consul_online_attempts",
+                "Number of attempts",
+                self.attempts as f64,
+            ),
+            (
+                "consul_online_last_run_timestamp_seconds",
+                "When the wait finished",
+                now.duration_since(SystemTime::UNIX_EPOCH)
+                    .unwrap_or_default()
+                    .as_secs_f64(),
+            ),
+        ];
+        metrics
+            .iter()
+            .map(|(name, help, value)| {
+                format!(
+                    "# HELP {} {}\n# TYPE {} gauge\n{} {}\n",
+                    name, help, name, name, value
+                )
+            })
+            .collect()
+    }
+}
+
+impl Observer for Report {
+    fn attempt(&mut self, attempt: &Attempt) {
+        self.attempts = attempt.number;
+        self.target = Some(attempt.target.to_owned());
+        self.last_status = attempt.status;
+        self.leader_changes = attempt.leader_changes;
+        self.peer = attempt.peer.or(self.peer);
+        self.history.record(attempt);
+        if let Some(NotReadyReason::PermissionDenied { permission }) = attempt.outcome.reason() {
+            self.missing_permission = Some(permission.clone().unwrap_or_default());
+        }
+        if let Some(reason) = attempt.outcome.reason() {
+            self.last_error = Some((attempt.check.to_string(), reason.kind(), reason.to_string()));
+        }
+        if let (Outcome::Online, Check::Raft) = (&attempt.outcome, attempt.check) {
+            self.online_body = attempt.body.map(str::to_owned);
+        }
+    }
+
+    fn online(&mut self, info: &AgentInfo) {
+        self.info = info.clone();
+    }
+}
+
+/// Replace the file at `path` with `contents`. Readers will either see the old or the new file, never a partial write.
+pub fn write_atomic(path: &str, contents: &str) -> std::io::Result<()> {
+    let path = Path::new(path);
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(format!(".{}.tmp", std::process::id()));
+    fs::write(&tmp, contents)
+        .and_then(|_| fs::File::open(&tmp)?.sync_all())
+        .and_then(|_| fs::rename(&tmp, path))
+        .inspect_err(|_| {
+            let _ = fs::remove_file(&tmp);
+        })
+}
@@ -0,0 +1,82 @@
+//! Progress of a wait that survives a restart of the process, see [Config::state_file](crate::Config::state_file)
+
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize)]
+pub(crate) struct SavedState {
+    /// [Config::redacted_hash](crate::Config::redacted_hash) of the configuration the state belongs to
+    pub config_hash: String,
+    /// When the first process started to wait, in seconds since the unix epoch
+    pub started: f64,
+    /// Rounds made so far by all processes
+    pub attempts: u64,
+}
+
+impl SavedState {
+    /// State of a wait that started `elapsed` ago
+    pub fn new(config_hash: &str, elapsed: Duration, attempts: u64) -> Self {
+        let now = SystemTime::now();
+        Self {
+            config_hash: config_hash.to_owned(),
+            started: now
+                .checked_sub(elapsed)
+                .unwrap_or(now)
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs_f64(),
+            attempts,
+        }
+    }
+
+    /// The state in `path` if it belongs to the configuration with `config_hash`. None if there is none
+    pub fn load(path: &str, config_hash: &str) -> Option<Self> {
+        let contents = match std::fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return None,
+            Err(e) => {
+                log::warn!("failed to read state file {}, starting over: {}", path, e);
+                return None;
+            }
+        };
+        match serde_json::from_str::<SavedState>(&contents) {
+            Ok(state) if state.config_hash == config_hash => Some(state),
+            Ok(_) => {
+                log::info!(
+                    "state file {} belongs to another configuration, starting over",
+                    path
+                );
+                None
+            }
+            Err(e) => {
+                log::warn!("failed to parse state file {}, starting over: {}", path, e);
+                None
+            }
+        }
+    }
+
+    pub fn started(&self) -> SystemTime {
+        Duration::try_from_secs_f64(self.started)
+            .ok()
+            .and_then(|since| UNIX_EPOCH.checked_add(since))
+            .unwrap_or_else(SystemTime::now)
+    }
+
+    pub fn save(&self, path: &str) {
+        let contents = serde_json::to_string(self).unwrap_or_default();
+        if let Err(e) = crate::report::write_atomic(path, &contents) {
+            log::warn!("failed to write state file {}: {}", path, e);
+        }
+    }
+
+    /// The wait is over, the next one starts from scratch
+    pub fn remove(path: &str) {
+        match std::fs::remove_file(path) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+                log::warn!("failed to remove state file {}: {}", path, e)
+            }
+            _ => {}
+        }
+    }
+}
@@ -0,0 +1,336 @@
+use std::collections::BTreeMap;
+use std::collections::VecDeque;
+use std::fmt::Write as _;
+use std::io;
+use std::io::BufRead;
+use std::io::BufReader;
+use std::io::Write;
+use std::net::SocketAddr;
+use std::net::TcpListener;
+use std::net::TcpStream;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::Duration;
+use std::time::SystemTime;
+
+use crate::events::Attempt;
+use crate::events::Observer;
+use crate::events::State;
+use crate::events::Transition;
+
+/// Last result of a check, kept until the check is evaluated again
+struct CheckResult {
+    check: String,
+    outcome: &'static str,
+    reason: Option<String>,
+    status: Option<u16>,
+    latency: Duration,
+    peer: Option<SocketAddr>,
+    checked_at: SystemTime,
+    /// When the check last went from online to not online or back
+    since: SystemTime,
+}
+
+/// A reported state change
+struct HistoryEntry {
+    at: SystemTime,
+    from: Option<State>,
+    to: State,
+    reason: Option<String>,
+}
+
+/// Number of state changes a [StatusBoard] remembers by default
+pub const DEFAULT_HISTORY: usize = 100;
+
+struct Board {
+    /// Last reported state and when it was reported
+    state: Option<(State, SystemTime, Option<String>)>,
+    checks: Vec<CheckResult>,
+    /// The last state changes, oldest first
+    history: VecDeque<HistoryEntry>,
+    history_size: usize,
+    metrics: Metrics,
+}
+
+/// Counters for /metrics, never reset
+#[derive(Default)]
+struct Metrics {
+    attempts: u64,
+    /// Attempts that were not online, by the kind of reason
+    failures: BTreeMap<&'static str, u64>,
+    last_status: Option<u16>,
+    changes: BTreeMap<&'static str, u64>,
+    /// How long the last offline period lasted until consul was online again
+    last_offline: Option<Duration>,
+}
+
+/// Caches the last result of every check, the reported state and the last state changes, for the status server.
+/// Clones share the same results
+#[derive(Clone)]
+pub struct StatusBoard(Arc<Mutex<Board>>);
+
+impl Default for StatusBoard {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl StatusBoard {
+    pub fn new() -> Self {
+        Self::with_history(DEFAULT_HISTORY)
+    }
+
+    /// Remember the last `history_size` state changes
+    pub fn with_history(history_size: usize) -> Self {
+        Self(Arc::new(Mutex::new(Board {
+            state: None,
+            checks: Vec::new(),
+            history: VecDeque::with_capacity(history_size.min(DEFAULT_HISTORY)),
+            history_size,
+            metrics: Metrics::default(),
+        })))
+    }
+
+    /// Whether consul was reported online last
+    pub fn online(&self) -> bool {
+        matches!(self.board().state, Some((State::Online, ..)))
+    }
+
+    /// Reported state and the cached results of the checks
+    pub fn to_json(&self) -> serde_json::Value {
+        let board = self.board();
+        let time = |t: SystemTime| humantime::format_rfc3339_millis(t).to_string();
+        serde_json::json!({
+            "status": board.state.as_ref().map_or("unknown", |(state, ..)| state.name()),
+            "since": board.state.as_ref().map(|(_, since, _)| time(*since)),
+            "reason": board.state.as_ref().and_then(|(.., reason)| reason.clone()),
+            "checks": board.checks.iter().map(|c| serde_json::json!({
+                "check": c.check,
+                "outcome": c.outcome,
+                "reason": c.reason,
+                "status": c.status,
+                "latency_ms": c.latency.as_millis() as u64,
+                "peer": c.peer.map(|p| p.to_string()),
+                "checked_at": time(c.checked_at),
+                "since": time(c.since),
+            })).collect::<Vec<_>>(),
+        })
+    }
+
+    /// The remembered state changes, oldest first, with how long each state lasted
+    pub fn history_json(&self) -> serde_json::Value {
+        let board = self.board();
+        let entries = board.history.iter().enumerate().map(|(i, entry)| {
+            let until = board.history.get(i + 1).map(|next| next.at);
+            serde_json::json!({
+                "timestamp": humantime::format_rfc3339_millis(entry.at).to_string(),
+                "status": entry.to.name(),
+                "previous": entry.from.map(|s| s.name()),
+                "reason": entry.reason,
+                "lasted_seconds": until.and_then(|u| u.duration_since(entry.at).ok()).map(|d| d.as_secs_f64()),
+            })
+        });
+        serde_json::Value::Array(entries.collect())
+    }
+
+    /// Counters and gauges in the prometheus text format
+    pub fn metrics(&self) -> String {
+        let board = self.board();
+        let metrics = &board.metrics;
+        let mut out = String::new();
+        let mut metric = |name: &str, kind: &str, help: &str, samples: &[(String, f64)]| {
+            let _ = writeln!(out, "# HELP {} {}\n# TYPE {} {}", name, help, name, kind);
+            for (labels, value) in samples {
+                let _ = writeln!(out, "{}{} {}", name, labels, value);
+            }
+        };
+        let labelled = |label: &str, counts: &BTreeMap<&'static str, u64>| {
+            counts
+                .iter()
+                .map(|(value, count)| (format!("{{{}=\"{}\"}}", label, value), *count as f64))
+                .collect::<Vec<_>>()
+        };
+        metric(
+            "consul_online_up",
+            "gauge",
+            "Whether consul was reported online last",
+            &[(
+                String::new(),
+                matches!(board.state, Some((State::Online, ..))) as u8 as f64,
+            )],
+        );
+        metric(
+            "consul_online_attempts_total",
+            "counter",
+            "Evaluated checks",
+            &[(String::new(), metrics.attempts as f64)],
+        );
+        metric(
+            "consul_online_failures_total",
+            "counter",
+            "Evaluated checks that were not online, by class",
+            &labelled("class", &metrics.failures),
+        );
+        metric(
+            "consul_online_last_http_status",
+            "gauge",
+            "Status code of the last response",
+            &metrics
+                .last_status
+                .map(|s| (String::new(), s as f64))
+                .into_iter()
+                .collect::<Vec<_>>(),
+        );
+        metric(
+            "consul_online_state_changes_total",
+            "counter",
+            "Reported changes between online and offline, by the new state",
+            &labelled("state", &metrics.changes),
+        );
+        metric(
+            "consul_online_last_offline_seconds",
+            "gauge",
+            "How long consul took to come online again the last time it was offline",
+            &metrics
+                .last_offline
+                .map(|d| (String::new(), d.as_secs_f64()))
+                .into_iter()
+                .collect::<Vec<_>>(),
+        );
+        out
+    }
+
+    fn board(&self) -> std::sync::MutexGuard<'_, Board> {
+        self.0.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl Observer for StatusBoard {
+    fn attempt(&mut self, attempt: &Attempt) {
+        let check = attempt.check.to_string();
+        let mut board = self.board();
+        board.metrics.attempts += 1;
+        if let Some(reason) = attempt.outcome.reason() {
+            *board.metrics.failures.entry(reason.kind()).or_default() += 1;
+        }
+        if attempt.status.is_some() {
+            board.metrics.last_status = attempt.status;
+        }
+        let previous = board.checks.iter().position(|c| c.check == check);
+        let online = attempt.outcome.reason().is_none();
+        let since = match previous.map(|i| &board.checks[i]) {
+            Some(c) if (c.reason.is_none()) == online => c.since,
+            _ => attempt.timestamp,
+        };
+        let result = CheckResult {
+            check,
+            outcome: attempt.outcome.name(),
+            reason: attempt.outcome.reason().map(|r| r.to_string()),
+            status: attempt.status,
+            latency: attempt.latency,
+            peer: attempt.peer,
+            checked_at: attempt.timestamp,
+            since,
+        };
+        match previous {
+            Some(i) => board.checks[i] = result,
+            None => board.checks.push(result),
+        }
+    }
+
+    fn changed(&mut self, transition: &Transition) {
+        let now = SystemTime::now();
+        let reason = transition.reason.map(str::to_owned);
+        let mut board = self.board();
+        if let (State::Online, Some((State::Offline, since, _))) = (transition.to, &board.state) {
+            board.metrics.last_offline = now.duration_since(*since).ok();
+        }
+        *board
+            .metrics
+            .changes
+            .entry(transition.to.name())
+            .or_default() += 1;
+        board.state = Some((transition.to, now, reason.clone()));
+        if board.history_size == 0 {
+            return;
+        }
+        if board.history.len() == board.history_size {
+            board.history.pop_front();
+        }
+        board.history.push_back(HistoryEntry {
+            at: now,
+            from: transition.from,
+            to: transition.to,
+            reason,
+        });
+    }
+}
+
+/// Serve the board over http in a background thread: a json breakdown at /status, the last state changes at
+/// /history, counters in the prometheus format at /metrics, and /healthz responding with 200 while consul is online and 503 otherwise. Returns the address the server listens on
+pub fn serve(addr: &str, board: StatusBoard) -> io::Result<SocketAddr> {
+    let listener = TcpListener::bind(addr)?;
+    let local = listener.local_addr()?;
+    log::info!("serve status on http://{}", local);
+    std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    if let Err(e) = respond(stream, &board) {
+                        log::debug!("status request failed: {}", e);
+                    }
+                }
+                Err(e) => log::debug!("failed to accept status connection: {}", e),
+            }
+        }
+    });
+    Ok(local)
+}
+
+fn respond(stream: TcpStream, board: &StatusBoard) -> io::Result<()> {
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+    let mut reader = BufReader::new(stream);
+    let mut request = String::new();
+    reader.read_line(&mut request)?;
+    // the headers are not needed, but must be read before responding
+    let mut header = String::new();
+    while reader.read_line(&mut header)? > 2 {
+        header.clear();
+    }
+    let path = request
+        .split_whitespace()
+        .nth(1)
+        .unwrap_or("/")
+        .split('?')
+        .next()
+        .unwrap_or("/");
+    let (status, content_type, body) = match path {
+        "/status" => (
+            "200 OK",
+            "application/json",
+            format!("{:#}\n", board.to_json()),
+        ),
+        "/history" => (
+            "200 OK",
+            "application/json",
+            format!("{:#}\n", board.history_json()),
+        ),
+        "/metrics" => ("200 OK", "text/plain; version=0.0.4", board.metrics()),
+        "/healthz" if board.online() => ("200 OK", "text/plain", "online\n".to_owned()),
+        "/healthz" => (
+            "503 Service Unavailable",
+            "text/plain",
+            "offline\n".to_owned(),
+        ),
+        _ => ("404 Not Found", "text/plain", "not found\n".to_owned()),
+    };
+    let mut stream = reader.into_inner();
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        content_type,
+        body.len(),
+        body
+    )
+}
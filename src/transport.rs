@@ -0,0 +1,234 @@
+use std::io;
+use std::io::Read;
+use std::io::Write;
+#[cfg(feature = "native-transport")]
+use std::net::IpAddr;
+#[cfg(feature = "native-transport")]
+use std::net::SocketAddr;
+#[cfg(feature = "native-transport")]
+use std::net::TcpStream;
+#[cfg(feature = "native-transport")]
+use std::sync::Arc;
+use std::time::Duration;
+use std::time::Instant;
+
+#[cfg(feature = "native-transport")]
+use rustls::pki_types::ServerName;
+#[cfg(feature = "native-transport")]
+use socket2::Domain;
+#[cfg(feature = "native-transport")]
+use socket2::Protocol;
+#[cfg(feature = "native-transport")]
+use socket2::Socket;
+#[cfg(feature = "native-transport")]
+use socket2::Type;
+
+#[cfg(feature = "native-transport")]
+use crate::probe::PreferredAddress;
+use crate::Error;
+use crate::Result;
+
+/// Time left until `deadline`, an error once it passed
+fn remaining(deadline: Instant) -> io::Result<Duration> {
+    deadline
+        .checked_duration_since(Instant::now())
+        .filter(|d| !d.is_zero())
+        .ok_or_else(|| io::Error::from(io::ErrorKind::TimedOut))
+}
+
+/// Send `request` over a connected `stream` and read the response until the stream ends, at most `limit` bytes.
+/// `set_timeout` applies the time left until `deadline` to the socket before every read and write, `failed` turns
+/// io errors into the error of the route. For routes to the agent ureq can't take itself, the connection is
+/// closed after every request
+pub(crate) fn exchange(
+    mut stream: impl Read + Write,
+    set_timeout: impl Fn(Duration) -> io::Result<()>,
+    failed: impl Fn(io::Error) -> Error,
+    request: &ureq::Request,
+    deadline: Instant,
+    limit: u64,
+) -> Result<Vec<u8>> {
+    let url = request.request_url().map_err(Error::Request)?;
+    let target = match url.as_url().query() {
+        Some(query) => format!("{}?{}", url.path(), query),
+        None => url.path().to_owned(),
+    };
+    let mut head = format!(
+        "{} {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n",
+        request.method(),
+        target,
+        url.host()
+    );
+    for name in request.header_names() {
+        if name.eq_ignore_ascii_case("host") || name.eq_ignore_ascii_case("connection") {
+            continue;
+        }
+        for value in request.all(&name) {
+            head.push_str(&format!("{}: {}\r\n", name, value));
+        }
+    }
+    head.push_str("\r\n");
+
+    set_timeout(remaining(deadline).map_err(&failed)?).map_err(&failed)?;
+    stream.write_all(head.as_bytes()).map_err(&failed)?;
+    stream.flush().map_err(&failed)?;
+    let mut response = Vec::new();
+    let mut buf = [0; 8192];
+    loop {
+        set_timeout(remaining(deadline).map_err(&failed)?).map_err(&failed)?;
+        match stream.read(&mut buf) {
+            Ok(0) => break,
+            Ok(n) => response.extend_from_slice(&buf[..n]),
+            // servers commonly close tls connections without close_notify once the response is sent
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof && !response.is_empty() => break,
+            Err(e) => return Err(failed(e)),
+        }
+        if response.len() as u64 > limit {
+            break;
+        }
+    }
+    Ok(response)
+}
+
+/// Parse a response read by [exchange]
+pub(crate) fn parse(response: &[u8]) -> Result<ureq::Response> {
+    String::from_utf8_lossy(response)
+        .parse::<ureq::Response>()
+        .map_err(Error::Request)
+}
+
+/// Local end of the connections to the agent, see [crate::Config::source_address]
+#[cfg(feature = "native-transport")]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Source {
+    Address(IpAddr),
+    /// Name of a network interface, only supported on linux
+    Interface(String),
+}
+
+#[cfg(feature = "native-transport")]
+impl Source {
+    pub fn parse(source: &str) -> Self {
+        match source.parse::<IpAddr>() {
+            Ok(ip) => Source::Address(ip),
+            Err(_) => Source::Interface(source.to_owned()),
+        }
+    }
+
+    /// Bind `socket` before it connects to `peer`, an error if the source can't reach it
+    fn bind(&self, socket: &Socket, peer: &SocketAddr) -> io::Result<()> {
+        match self {
+            Source::Address(ip) if ip.is_ipv4() != peer.is_ipv4() => Err(io::Error::new(
+                io::ErrorKind::AddrNotAvailable,
+                format!("{} can't connect to {}", ip, peer),
+            )),
+            Source::Address(ip) => socket.bind(&SocketAddr::new(*ip, 0).into()),
+            #[cfg(any(target_os = "android", target_os = "fuchsia", target_os = "linux"))]
+            Source::Interface(name) => socket.bind_device(Some(name.as_bytes())),
+            #[cfg(not(any(target_os = "android", target_os = "fuchsia", target_os = "linux")))]
+            Source::Interface(_) => Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "binding to an interface is only supported on linux, use its address instead",
+            )),
+        }
+    }
+}
+
+#[cfg(feature = "native-transport")]
+impl std::fmt::Display for Source {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Source::Address(ip) => write!(f, "{}", ip),
+            Source::Interface(name) => write!(f, "{}", name),
+        }
+    }
+}
+
+/// Connects to the agent from a [Source], with tls if there is a config for it.
+/// Does not follow redirects and does not go through proxies
+#[cfg(feature = "native-transport")]
+pub(crate) struct BoundConnector {
+    source: Source,
+    resolver: PreferredAddress,
+    tls: Option<Arc<rustls::ClientConfig>>,
+}
+
+#[cfg(feature = "native-transport")]
+impl BoundConnector {
+    pub fn new(
+        source: Source,
+        resolver: PreferredAddress,
+        tls: Option<Arc<rustls::ClientConfig>>,
+    ) -> Self {
+        Self {
+            source,
+            resolver,
+            tls,
+        }
+    }
+
+    /// Send `request` and read the whole response, giving up after `timeout`. Returns the address that answered
+    pub fn call(
+        &self,
+        request: &ureq::Request,
+        timeout: Duration,
+        limit: u64,
+    ) -> Result<(ureq::Response, SocketAddr)> {
+        let deadline = Instant::now() + timeout;
+        let url = request.request_url().map_err(Error::Request)?;
+        let port = url.as_url().port_or_known_default().unwrap_or(80);
+        let host = url.host().trim_start_matches('[').trim_end_matches(']');
+        let netloc = format!("{}:{}", url.host(), port);
+        let addrs = self
+            .resolver
+            .resolve(&netloc)
+            .map_err(|e| crate::resolve_error(&netloc, e))?;
+        let mut last_error = None;
+        for addr in addrs {
+            let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))
+                .and_then(|socket| self.source.bind(&socket, &addr).map(|_| socket));
+            let socket = match socket {
+                Ok(socket) => socket,
+                Err(e) => {
+                    last_error = Some(Error::SourceAddress(self.source.to_string(), e));
+                    continue;
+                }
+            };
+            let connected = remaining(deadline)
+                .and_then(|timeout| socket.connect_timeout(&addr.into(), timeout));
+            if let Err(e) = connected {
+                log::debug!("connect to {} from {}: {}", addr, self.source, e);
+                last_error = Some(Error::Connection(addr.to_string(), e));
+                continue;
+            }
+            let stream = TcpStream::from(socket);
+            let timeouts = stream
+                .try_clone()
+                .map_err(|e| Error::Connection(addr.to_string(), e))?;
+            let set_timeout = |timeout| {
+                timeouts.set_read_timeout(Some(timeout))?;
+                timeouts.set_write_timeout(Some(timeout))
+            };
+            let failed = |e| Error::Connection(addr.to_string(), e);
+            let response = match &self.tls {
+                Some(tls) => {
+                    let name = ServerName::try_from(host.to_owned()).map_err(|e| {
+                        Error::General(format!("invalid server name {}: {}", host, e))
+                    })?;
+                    let connection = rustls::ClientConnection::new(tls.clone(), name)
+                        .map_err(|e| Error::Connection(addr.to_string(), io::Error::other(e)))?;
+                    let stream = rustls::StreamOwned::new(connection, stream);
+                    exchange(stream, set_timeout, failed, request, deadline, limit)?
+                }
+                None => exchange(stream, set_timeout, failed, request, deadline, limit)?,
+            };
+            return Ok((parse(&response)?, addr));
+        }
+        Err(last_error.unwrap_or_else(|| {
+            Error::Connection(
+                netloc,
+                io::Error::new(io::ErrorKind::NotFound, "no addresses"),
+            )
+        }))
+    }
+}
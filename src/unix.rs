@@ -0,0 +1,43 @@
+use std::path::Path;
+use std::time::Duration;
+
+use crate::Error;
+use crate::Result;
+
+/// Socket path of a "unix:///path/to/socket" or "unix:/path/to/socket" address
+pub(crate) fn socket_path(addr: &str) -> Option<&str> {
+    addr.strip_prefix("unix://")
+        .or_else(|| addr.strip_prefix("unix:"))
+        .filter(|path| path.starts_with('/'))
+}
+
+/// Send `request` over the unix socket at `path` and read the whole response, giving up after `timeout`
+#[cfg(unix)]
+pub(crate) fn call(
+    path: &Path,
+    request: &ureq::Request,
+    timeout: Duration,
+    limit: u64,
+) -> Result<ureq::Response> {
+    let failed = |e| Error::UnixSocket(path.display().to_string(), e);
+    let deadline = std::time::Instant::now() + timeout;
+    let stream = std::os::unix::net::UnixStream::connect(path).map_err(failed)?;
+    let timeouts = stream.try_clone().map_err(failed)?;
+    let set_timeout = |timeout| {
+        timeouts.set_read_timeout(Some(timeout))?;
+        timeouts.set_write_timeout(Some(timeout))
+    };
+    let response =
+        crate::transport::exchange(stream, set_timeout, failed, request, deadline, limit)?;
+    crate::transport::parse(&response)
+}
+
+#[cfg(not(unix))]
+pub(crate) fn call(
+    _path: &Path,
+    _request: &ureq::Request,
+    _timeout: Duration,
+    _limit: u64,
+) -> Result<ureq::Response> {
+    Err(Error::UnixSocketUnsupported)
+}